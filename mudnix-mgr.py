@@ -1,25 +1,37 @@
 #!/usr/bin/env python3
 import sys
 import json
+import sqlite3
 from datetime import datetime
 
 
-class UsersFile:
+ROLES = ["player", "builder", "moderator", "admin"]
+
+
+class UsersDb:
   def __init__(self, path):
-    self._path = path
-    self.data = None
+    self._connection = sqlite3.connect(path)
 
-  def load(self):
-    file = open(self._path)
-    self.data = json.loads(file.read())
-    file.close()
+  def users(self):
+    return [
+      json.loads(data) for (data,) in self._connection.execute(
+        "SELECT data FROM users ORDER BY username"
+      )
+    ]
 
-  def save(self, of=None):
-    if self.data is not None:
-      out_path = self._path if of is None else of
-      out_file = open(out_path, "w+")
-      out_file.write(json.dumps(self.data, indent=2))
-      out_file.close()
+  def save_user(self, user):
+    with self._connection:
+      self._connection.execute(
+        "UPDATE users SET data = ? WHERE username = ?",
+        (json.dumps(user), user["username"])
+      )
+
+  def delete_users(self, usernames):
+    with self._connection:
+      self._connection.executemany(
+        "DELETE FROM users WHERE username = ?",
+        [(username,) for username in usernames]
+      )
 
 
 def print_user(user):
@@ -32,6 +44,7 @@ def print_user(user):
   print(f"User: {user['username']}")
   print(f"* Account created:\t{creation_time}")
   print(f"* Last time active:\t{active_time}")
+  print(f"* Role:\t\t\t{user.get('role', 'player')}")
   print(f"* Current location:\t{user['world_location']}\n")
 
 
@@ -44,34 +57,34 @@ def print_current_time():
 
 
 def main(argv):
-  users_file = UsersFile("users.json")
+  users_db = UsersDb("users.db")
   if len(argv) < 2:
     print(
       f"usage: {argv[0]} "
-      "{ --list | (--userdel | --userinfo) <users>... }"
+      "{ --list | (--userdel | --userinfo) <users>... "
+      "| --setrole <user> (player | builder | moderator | admin) }"
     )
   elif argv[1] == "--list":
     print_current_time()
-    users_file.load()
-    for user in users_file.data["users"]:
+    for user in users_db.users():
       print_user(user)
   elif argv[1] == "--userdel":
-    users_file.load()
-    users_to_delete = argv[2:]
-    users_file.data["users"] = [
-      user for user in users_file.data["users"] if (
-        user["username"] not in users_to_delete
-      )
-    ]
-    users_file.save()
+    users_db.delete_users(argv[2:])
   elif argv[1] == "--userinfo":
     print_current_time()
-    users_file.load()
     users_to_print = argv[2:]
     for user in filter(
-      lambda u: u["username"] in users_to_print, users_file.data["users"]
+      lambda u: u["username"] in users_to_print, users_db.users()
     ):
       print_user(user)
+  elif argv[1] == "--setrole":
+    if len(argv) != 4 or argv[3] not in ROLES:
+      print(f"usage: {argv[0]} --setrole <user> ({' | '.join(ROLES)})")
+      return
+    for user in users_db.users():
+      if user["username"] == argv[2]:
+        user["role"] = argv[3]
+        users_db.save_user(user)
 
 
 if __name__ == "__main__":
@@ -0,0 +1,130 @@
+/**
+ * API endpoints for builders to change the world map while the server is
+ * running. The edits themselves live in `world_builder`. Sublocations are
+ * given as location IDs (e.g. `Foo_Pond::shore`), and WorldLocations by name.
+ */
+use rocket::State;
+use rocket::response::content;
+
+use crate::world_map;
+use crate::world_builder;
+use crate::session;
+use crate::error::JsonResult;
+
+fn built(username: &str, info: String, warnings: Vec<String>) -> JsonResult {
+  Ok(content::Json(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": info,
+    "warnings": warnings
+  }).to_string()))
+}
+
+#[post("/location?<name>&<eco>&<urban>&<spawn_rate>")]
+pub fn create_location(
+  builder: session::Authorized<session::CanBuildWorld>,
+  name: &str,
+  eco: &str,
+  urban: Option<bool>,
+  spawn_rate: Option<f32>,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>
+) -> JsonResult {
+  let biome = world_map::Biome {
+    eco: String::from(eco),
+    urban: urban.unwrap_or(false)
+  };
+  let warnings = world_builder.edit(world, |map| {
+    world_builder.create_location(map, name, biome.clone(), spawn_rate.unwrap_or(0.0))
+  })?;
+  built(&builder.user.username, format!("Created {}.", name), warnings)
+}
+
+#[post("/sublocation?<location>&<name>&<t>&<description>")]
+pub fn add_sublocation(
+  builder: session::Authorized<session::CanBuildWorld>,
+  location: &str,
+  name: &str,
+  t: &str,
+  description: Option<&str>,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>
+) -> JsonResult {
+  let warnings = world_builder.edit(world, |map| {
+    world_builder::add_sublocation(map, location, name, t, description.unwrap_or_default())
+  })?;
+  built(&builder.user.username, format!("Added {}::{}.", location, name), warnings)
+}
+
+// nobody can be standing in a sublocation while it's renamed or removed
+#[post("/rename?<location>&<name>")]
+pub fn rename_sublocation(
+  builder: session::Authorized<session::CanBuildWorld>,
+  location: &str,
+  name: &str,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>
+) -> JsonResult {
+  let warnings = world_builder.edit(world, |map| {
+    world_builder::rename_sublocation(map, location, name)
+  })?;
+  built(&builder.user.username, format!("Renamed {} to {}.", location, name), warnings)
+}
+
+#[post("/remove?<location>")]
+pub fn remove_sublocation(
+  builder: session::Authorized<session::CanBuildWorld>,
+  location: &str,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>
+) -> JsonResult {
+  let warnings = world_builder.edit(world, |map| {
+    world_builder::remove_sublocation(map, location)
+  })?;
+  built(&builder.user.username, format!("Removed {}.", location), warnings)
+}
+
+#[post("/link?<from>&<to>&<dir>")]
+pub fn link(
+  builder: session::Authorized<session::CanBuildWorld>,
+  from: &str,
+  to: &str,
+  dir: Option<world_map::Direction>,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>
+) -> JsonResult {
+  let warnings = world_builder.edit(world, |map| {
+    world_builder::link(map, from, to, dir)
+  })?;
+  built(&builder.user.username, format!("Linked {} and {}.", from, to), warnings)
+}
+
+#[post("/unlink?<from>&<to>")]
+pub fn unlink(
+  builder: session::Authorized<session::CanBuildWorld>,
+  from: &str,
+  to: &str,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>
+) -> JsonResult {
+  let warnings = world_builder.edit(world, |map| {
+    world_builder::unlink(map, from, to)
+  })?;
+  built(&builder.user.username, format!("Unlinked {} and {}.", from, to), warnings)
+}
+
+#[post("/attrs?<location>&<spawn_rate>&<eco>&<urban>")]
+pub fn set_attrs(
+  builder: session::Authorized<session::CanBuildWorld>,
+  location: &str,
+  spawn_rate: Option<f32>,
+  eco: Option<&str>,
+  urban: Option<bool>,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>
+) -> JsonResult {
+  let warnings = world_builder.edit(world, |map| {
+    world_builder::set_attrs(map, location, spawn_rate, eco, urban)
+  })?;
+  built(&builder.user.username, format!("Updated {}.", location), warnings)
+}
@@ -0,0 +1,223 @@
+/**
+ * Append-only record of everything said in the game, kept so that
+ * moderators can settle disputes and players can catch up on what happened
+ * while they were away. Each location has its own directory with one file
+ * of JSON lines per day (UTC), e.g. `chat_logs/Quux_Plains/northern_region/2022-03-14.jsonl`.
+ */
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::message::Message;
+use crate::error::MudnixError;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+// the UTC date of a Unix timestamp as YYYY-MM-DD, which also sorts by date
+pub fn date_of(timestamp: u64) -> String {
+  // from Howard Hinnant's days-to-civil algorithm
+  let days = (timestamp / SECONDS_PER_DAY) as i64 + 719468;
+  let era = days.div_euclid(146097);
+  let day_of_era = days.rem_euclid(146097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month_index = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+  let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// what to look for in `ChatLog::search`; every field is optional
+pub struct SearchQuery<'a> {
+  pub user: Option<&'a str>,
+  pub location_id: Option<&'a str>,
+  pub since: Option<u64>, // Unix timestamps, inclusive
+  pub until: Option<u64>,
+  pub limit: usize
+}
+
+#[derive(Clone)]
+pub struct ChatLog {
+  dir: PathBuf
+}
+
+impl ChatLog {
+  pub fn new(dir: &str) -> Self {
+    Self { dir: PathBuf::from(dir) }
+  }
+
+  // location IDs come from clients too, so only plain names are turned into paths
+  fn location_dir(&self, location_id: &str) -> Result<PathBuf, MudnixError> {
+    let mut dir = self.dir.clone();
+    for part in location_id.split("::") {
+      if part.is_empty()
+        || !part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(MudnixError::InvalidLocation(format!("{} is not a location", location_id)));
+      }
+      dir.push(part);
+    }
+    Ok(dir)
+  }
+
+  pub fn append(&self, message: &Message) -> Result<(), MudnixError> {
+    let dir = self.location_dir(&message.location_id)?;
+    fs::create_dir_all(&dir)?;
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(dir.join(format!("{}.jsonl", date_of(message.timestamp))))?;
+    let line = serde_json::to_string(message)
+      .map_err(|e| MudnixError::Storage(e.to_string()))?;
+    // one write per line, so concurrent appends don't interleave
+    file.write_all(format!("{}\n", line).as_bytes())?;
+    Ok(())
+  }
+
+  // the day files in a location's directory, oldest first
+  fn day_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+      Ok(entries) => entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+        .collect(),
+      Err(_) => vec![]
+    };
+    files.sort();
+    files
+  }
+
+  // lines that can't be parsed, e.g. one cut short by a crash, are skipped
+  fn read_day(path: &Path) -> Vec<Message> {
+    match fs::File::open(path) {
+      Ok(file) => BufReader::new(file).lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect(),
+      Err(_) => vec![]
+    }
+  }
+
+  /* The public messages said at a location, newest first, `count` at a time.
+     Page 0 is the most recent. */
+  pub fn recent(
+    &self,
+    location_id: &str,
+    count: usize,
+    page: usize
+  ) -> Result<Vec<Message>, MudnixError> {
+    let wanted = count.saturating_mul(page.saturating_add(1));
+    let mut messages: Vec<Message> = vec![];
+    for path in ChatLog::day_files(&self.location_dir(location_id)?).iter().rev() {
+      let mut day = ChatLog::read_day(path);
+      // whispers and channel messages are logged where they were sent, but aren't public
+      day.retain(|message| message.recipient.is_none() && message.channel.is_none());
+      messages.extend(day.into_iter().rev());
+      if messages.len() >= wanted {
+        break;
+      }
+    }
+    Ok(messages.into_iter().skip(count.saturating_mul(page)).take(count).collect())
+  }
+
+  // the directories in `dir`, e.g. a WorldLocation's sublocations
+  fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(dir) {
+      Ok(entries) => entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect(),
+      Err(_) => vec![]
+    }
+  }
+
+  /* Every logged message matching the query, oldest first, up to
+     `query.limit`. `query.location_id` may be a sublocation or a whole
+     WorldLocation. Days are read one at a time, oldest first, until there
+     are enough messages. */
+  pub fn search(&self, query: &SearchQuery) -> Result<Vec<Message>, MudnixError> {
+    let dirs: Vec<PathBuf> = match query.location_id {
+      Some(location_id) if location_id.contains("::") => vec![self.location_dir(location_id)?],
+      // each WorldLocation holds a directory per sublocation
+      Some(location_id) => ChatLog::subdirs(&self.location_dir(location_id)?),
+      None => ChatLog::subdirs(&self.dir).iter().flat_map(|dir| ChatLog::subdirs(dir)).collect()
+    };
+    let first_day = query.since.map(date_of);
+    let last_day = query.until.map(date_of);
+
+    // the day files to search, by day, across every directory
+    let mut days: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for path in dirs.iter().flat_map(|dir| ChatLog::day_files(dir)) {
+      let day = String::from(path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default());
+      if first_day.as_ref().is_some_and(|first| day < *first)
+        || last_day.as_ref().is_some_and(|last| day > *last) {
+        continue;
+      }
+      days.entry(day).or_default().push(path);
+    }
+
+    let mut messages: Vec<Message> = vec![];
+    for paths in days.values() {
+      let mut day: Vec<Message> = paths.iter()
+        .flat_map(|path| ChatLog::read_day(path))
+        .filter(|message| {
+          query.user.is_none_or(|user| message.user == user)
+            && query.since.is_none_or(|since| message.timestamp >= since)
+            && query.until.is_none_or(|until| message.timestamp <= until)
+        })
+        .collect();
+      day.sort_by_key(|message| message.timestamp);
+      messages.extend(day);
+      if messages.len() >= query.limit {
+        break;
+      }
+    }
+    messages.truncate(query.limit);
+    Ok(messages)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn dates_of_known_timestamps() {
+    assert_eq!(date_of(0), "1970-01-01");
+    assert_eq!(date_of(951782400), "2000-02-29");
+    assert_eq!(date_of(951868800), "2000-03-01");
+    assert_eq!(date_of(1647216000 + SECONDS_PER_DAY - 1), "2022-03-14");
+    assert_eq!(date_of(4102444799), "2099-12-31");
+    assert_eq!(date_of(4107542400), "2100-03-01"); // 2100 isn't a leap year
+  }
+
+  fn message_at(text: &str, location_id: &str, timestamp: u64) -> Message {
+    Message { timestamp, ..Message::new(text, "alice", location_id) }
+  }
+
+  #[test]
+  fn search_by_world_location_covers_its_sublocations() {
+    let dir = std::env::temp_dir().join(format!("mudnix_chat_log_test_{}", std::process::id()));
+    let log = ChatLog::new(&format!("{}", dir.display()));
+    log.append(&message_at("first", "Quux_Plains::northern_region", 0)).unwrap();
+    log.append(&message_at("second", "Quux_Plains::central_region", 10)).unwrap();
+    log.append(&message_at("third", "Quux_Plains::northern_region", SECONDS_PER_DAY)).unwrap();
+    log.append(&message_at("elsewhere", "Foo_Pond::shore", 20)).unwrap();
+
+    let search = |location_id: Option<&str>, limit: usize| -> Vec<String> {
+      log.search(&SearchQuery { user: None, location_id, since: None, until: None, limit })
+        .unwrap()
+        .into_iter()
+        .map(|message| message.text)
+        .collect()
+    };
+    assert_eq!(search(Some("Quux_Plains"), 10), vec!["first", "second", "third"]);
+    assert_eq!(search(Some("Quux_Plains::northern_region"), 10), vec!["first", "third"]);
+    assert_eq!(search(None, 3), vec!["first", "second", "elsewhere"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,94 @@
+/**
+ * Server configuration, read from the `[default.mudnix]` section of
+ * Rocket.toml (or the profile in use). Any value can be overridden with a
+ * `MUDNIX_` environment variable, e.g. `MUDNIX_DATA_ROOT=/srv/mudnix`.
+ */
+use std::path::Path;
+use rocket::figment::{Figment, providers::{Env, Serialized}};
+use serde::{Serialize, Deserialize};
+
+// what to do when the world map fails validation at launch
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorldValidation {
+  Warn, // log the problems and launch anyway
+  Strict // refuse to launch
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MudnixConfig {
+  // relative paths below are resolved against this directory
+  pub data_root: String,
+  pub map_dir: String,
+  pub item_dir: String,
+  pub loot_dir: String,
+  pub static_dir: String,
+  pub users_file: String, // SQLite database
+  pub legacy_users_file: String, // users.json, imported when the database is first created
+  pub chat_log_dir: String,
+  pub spawn_location: String, // where new players start
+  pub world_validation: WorldValidation,
+  pub travel_step_seconds: u64, // how long each step of /game/travel takes
+  pub loot_seed: Option<u64>, // set to get the same treasure chests every run, e.g. for testing
+  pub websocket_port: u16, // the game channel listens on the same address as the API
+  pub chat_history_size: usize, // messages kept per location for clients that reconnect
+  pub max_message_length: usize, // in characters
+  // each user may send `chat_burst` messages at once, refilled at `chat_messages_per_minute`
+  pub chat_burst: u32,
+  pub chat_messages_per_minute: u32,
+  pub filtered_words: Vec<String> // replaced with asterisks in every message
+}
+
+impl Default for MudnixConfig {
+  fn default() -> Self {
+    Self {
+      data_root: String::from("."),
+      map_dir: String::from("map"),
+      item_dir: String::from("items"),
+      loot_dir: String::from("loot"),
+      static_dir: String::from("static"),
+      users_file: String::from("users.db"),
+      legacy_users_file: String::from("users.json"),
+      chat_log_dir: String::from("chat_logs"),
+      spawn_location: String::from("Quux_Plains::northern_region"),
+      world_validation: WorldValidation::Warn,
+      travel_step_seconds: 2,
+      loot_seed: None,
+      websocket_port: 8081,
+      chat_history_size: 100,
+      max_message_length: 500,
+      chat_burst: 5,
+      chat_messages_per_minute: 20,
+      filtered_words: vec![]
+    }
+  }
+}
+
+impl MudnixConfig {
+  pub fn from_figment(figment: &Figment) -> Result<Self, Box<rocket::figment::Error>> {
+    let mut config: Self = Figment::from(Serialized::defaults(Self::default()))
+      .merge(figment.focus("mudnix"))
+      .merge(Env::prefixed("MUDNIX_"))
+      .extract()
+      .map_err(Box::new)?;
+    config.resolve_paths();
+    Ok(config)
+  }
+
+  fn resolve_paths(&mut self) {
+    let data_root = Path::new(&self.data_root).to_path_buf();
+    for path in [
+      &mut self.map_dir,
+      &mut self.item_dir,
+      &mut self.loot_dir,
+      &mut self.static_dir,
+      &mut self.users_file,
+      &mut self.legacy_users_file,
+      &mut self.chat_log_dir
+    ] {
+      if Path::new(path.as_str()).is_relative() {
+        *path = format!("{}", data_root.join(path.as_str()).display());
+      }
+    }
+  }
+}
@@ -1,10 +1,16 @@
-//extern crate rand;
-//use crate::rand::Rng;
+use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use serde::{Serialize, Deserialize};
 use serde_json;
 
+use crate::loot;
+use crate::mudnix_utils;
+use crate::world_map::Biome;
+
 pub type Inventory = Vec<Item>;
 pub trait ItemContainer {
   fn add_item(&mut self, item: &Item);
@@ -12,15 +18,16 @@ pub trait ItemContainer {
 }
 impl ItemContainer for Inventory {
   fn add_item(&mut self, item: &Item) {
-    if let Some(i) = self.iter().position(|_item| _item.t == item.t) {
-      self[i].qty += 1;
+    if let Some(i) = self.iter().position(|_item| _item.stacks_with(item)) {
+      // saturating, since an overflow would abort the server
+      self[i].qty = self[i].qty.saturating_add(item.qty);
     } else {
       self.push(item.clone());
     }
   }
 
   fn remove_item(&mut self, item: &Item) {
-    if let Some(i) = self.iter().position(|_item| _item.t == item.t) {
+    if let Some(i) = self.iter().position(|_item| _item.stacks_with(item)) {
       if self[i].qty > 1 {
         self[i].qty -= 1;
       } else {
@@ -30,7 +37,7 @@ impl ItemContainer for Inventory {
   }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Npc {
   pub name: String,
   // TODO finish implementation
@@ -55,36 +62,191 @@ pub trait Entity {
   }
 }
 
+/* A stack of items of one type, as kept in inventories and on the ground.
+   Only the type is stored; what it is called, what it looks like and how
+   rare it is come from its ItemTemplate. Items with instance data of their
+   own, like a worn-down sword, only stack with identical ones. */
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Item {
   pub t: String,
   pub qty: u32,
-  pub name: String,
-  pub description: String,
-  pub rarity: String
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub durability: Option<u32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub custom_name: Option<String> // e.g. a name the player has engraved on it
 }
 
 impl Item {
-  pub fn path_of(item_type: &str) -> String {
-    format!("/home/runner/mudnix/items/{}.json", item_type)
-  }
-
-  // pub fn new(item_type: &str, name: &str, description: &str, rarity: &str) -> Self {
-  //   Self {
-  //     t: String::from(item_type),
-  //     qty: 1,
-  //     name: String::from(name),
-  //     description: String::from(description),
-  //     rarity: String::from(rarity)
-  //   }
-  // }
+  pub fn new(item_type: &str, qty: u32) -> Self {
+    Self {
+      t: String::from(item_type),
+      qty,
+      durability: None,
+      custom_name: None
+    }
+  }
+
+  fn stacks_with(&self, other: &Item) -> bool {
+    self.t == other.t && self.durability == other.durability && self.custom_name == other.custom_name
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rarity {
+  Common,
+  Uncommon,
+  Rare,
+  Epic,
+  Legendary
+}
+
+impl Rarity {
+  // how often an item turns up in loot, relative to others, unless its loot table says otherwise
+  pub fn weight(self) -> u32 {
+    match self {
+      Rarity::Common => 100,
+      Rarity::Uncommon => 40,
+      Rarity::Rare => 15,
+      Rarity::Epic => 5,
+      Rarity::Legendary => 1
+    }
+  }
+}
 
+// the definition of a type of item, read from `items/<t>.json`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ItemTemplate {
+  pub t: String,
+  pub name: String,
+  pub description: String,
+  pub rarity: Rarity
+}
+
+impl ItemTemplate {
   pub fn from_file(file_path: &str) -> Result<Self, io::Error> {
-    let original_json = match fs::read_to_string(file_path) {
-      Ok(file_contents) => file_contents,
-      Err(e) => return Err(e)
+    mudnix_utils::read_json_with_backup(file_path)
+  }
+}
+
+/**
+ * Every ItemTemplate, by type, and the loot tables that say where they turn
+ * up, read once at launch and again when the data is reloaded. Items whose
+ * type isn't known (e.g. one that has been removed from `items/`) are still
+ * shown, by their type, rather than failing. Clones share the same registry,
+ * including the random number generator treasure chests are rolled with.
+ */
+#[derive(Clone)]
+pub struct ItemRegistry {
+  templates: Arc<RwLock<HashMap<String, ItemTemplate>>>,
+  loot_tables: Arc<RwLock<Vec<loot::LootTable>>>,
+  rng: Arc<Mutex<StdRng>>
+}
+
+impl ItemRegistry {
+  // with a seed, the same treasure chests turn up in the same order every run
+  pub fn new(
+    templates: HashMap<String, ItemTemplate>,
+    loot_tables: Vec<loot::LootTable>,
+    seed: Option<u64>
+  ) -> Self {
+    Self {
+      templates: Arc::new(RwLock::new(templates)),
+      loot_tables: Arc::new(RwLock::new(loot_tables)),
+      rng: Arc::new(Mutex::new(match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+      }))
+    }
+  }
+
+  /* Reads every item definition in the item directory, along with what is
+     wrong with any that can't be used. Items are looked up by `t`, so each
+     file must be named after the item's `t`. */
+  pub fn read(item_dir: &str) -> (HashMap<String, ItemTemplate>, Vec<String>) {
+    let mut templates: HashMap<String, ItemTemplate> = HashMap::new();
+    let mut problems: Vec<String> = vec![];
+    let entries = match fs::read_dir(item_dir) {
+      Ok(entries) => entries,
+      Err(e) => return (templates, vec![format!("{}: {}", item_dir, e)])
     };
-    Ok(serde_json::from_str(&original_json).expect("unable to parse json"))
+    let paths = entries.filter_map(|entry| entry.ok())
+      .map(|entry| entry.path())
+      .filter(|path| path.extension().is_some_and(|extension| extension == "json"));
+    for path in paths {
+      let file_stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+      match ItemTemplate::from_file(&format!("{}", path.display())) {
+        Ok(template) if template.t == file_stem => {
+          templates.insert(template.t.clone(), template);
+        },
+        Ok(template) => problems.push(format!("{} defines {}", path.display(), template.t)),
+        Err(e) => problems.push(format!("{}: {}", path.display(), e))
+      }
+    }
+    (templates, problems)
+  }
+
+  pub fn replace(&self, templates: HashMap<String, ItemTemplate>, loot_tables: Vec<loot::LootTable>) {
+    // both locks are held at once, so nobody sees new loot tables with old templates
+    let mut current_templates = self.templates.write().unwrap();
+    let mut current_loot_tables = self.loot_tables.write().unwrap();
+    *current_templates = templates;
+    *current_loot_tables = loot_tables;
+  }
+
+  pub fn count(&self) -> usize {
+    self.templates.read().unwrap().len()
+  }
+
+  pub fn get(&self, item_type: &str) -> Option<ItemTemplate> {
+    self.templates.read().unwrap().get(item_type).cloned()
+  }
+
+  // what a treasure chest at the given location holds, from the loot table for it
+  pub fn roll_loot(&self, location_id: &str, eco: &str, rng: &mut impl Rng) -> Inventory {
+    let templates = self.templates.read().unwrap();
+    let loot_tables = self.loot_tables.read().unwrap();
+    match loot::table_for(&loot_tables, location_id, eco) {
+      Some(table) => table.roll(&templates, rng),
+      None => vec![]
+    }
+  }
+
+  /* Rolls for a treasure chest at `location_id`, which turns up with a
+     chance of `spawn_rate` and is filled from the loot table for it. */
+  pub fn roll_chest(&self, location_id: &str, biome: &Biome, spawn_rate: f32) -> Option<TreasureChest> {
+    let mut rng = self.rng.lock().unwrap();
+    if rng.gen_range(0.0..1.0) < spawn_rate {
+      TreasureChest::new(location_id, biome, self, &mut *rng)
+    } else {
+      None
+    }
+  }
+
+  // the name players see for the item
+  pub fn name_of(&self, item: &Item) -> String {
+    match (&item.custom_name, self.get(&item.t)) {
+      (Some(custom_name), _) => custom_name.clone(),
+      (None, Some(template)) => template.name,
+      (None, None) => item.t.replace('_', " ")
+    }
+  }
+
+  // the item along with its template, for clients
+  pub fn describe(&self, item: &Item) -> serde_json::Value {
+    let template = self.get(&item.t);
+    serde_json::json!({
+      "t": item.t,
+      "qty": item.qty,
+      "name": self.name_of(item),
+      "description": template.as_ref().map(|template| template.description.clone()),
+      "rarity": template.as_ref().map(|template| template.rarity),
+      "durability": item.durability
+    })
+  }
+
+  pub fn describe_all(&self, inventory: &Inventory) -> Vec<serde_json::Value> {
+    inventory.iter().map(|item| self.describe(item)).collect()
   }
 }
 
@@ -100,16 +262,66 @@ pub struct TreasureChest {
 }
 
 impl TreasureChest {
-  pub fn new() -> Self {
-    let mut result = TreasureChest { contents: vec![] };
-    // TODO randomize item spawning
-    result.contents.add_item(
-      &Item::from_file(&Item::path_of("bar_of_soap")).unwrap()
-    );
-    result
+  /* A chest found at `destination`, filled from its loot table. None if
+     there's no table for it or nothing came up. */
+  pub fn new(
+    destination: &str,
+    biome: &Biome,
+    items: &ItemRegistry,
+    rng: &mut impl Rng
+  ) -> Option<Self> {
+    let contents = items.roll_loot(destination, &biome.eco, rng);
+    if contents.is_empty() {
+      None
+    } else {
+      Some(TreasureChest { contents })
+    }
   }
 }
 
 impl Entity for TreasureChest {
   // TODO
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn items_of_a_type_stack() {
+    let mut inventory: Inventory = vec![];
+    inventory.add_item(&Item::new("copper_coin", 3));
+    inventory.add_item(&Item::new("copper_coin", 2));
+    inventory.add_item(&Item::new("bar_of_soap", 1));
+    assert_eq!(inventory.len(), 2);
+    assert_eq!(inventory[0].qty, 5);
+  }
+
+  #[test]
+  fn items_with_their_own_data_only_stack_with_identical_ones() {
+    let worn = Item { durability: Some(10), ..Item::new("sword", 1) };
+    let named = Item { custom_name: Some(String::from("Biter")), ..Item::new("sword", 1) };
+    let mut inventory: Inventory = vec![];
+    for item in [&worn, &named, &Item::new("sword", 1), &worn] {
+      inventory.add_item(item);
+    }
+    assert_eq!(inventory.len(), 3);
+    assert_eq!(inventory[0].qty, 2);
+  }
+
+  #[test]
+  fn stacks_stop_at_the_most_a_qty_can_hold() {
+    let mut inventory: Inventory = vec![Item::new("copper_coin", u32::MAX - 1)];
+    inventory.add_item(&Item::new("copper_coin", 5));
+    assert_eq!(inventory[0].qty, u32::MAX);
+  }
+
+  #[test]
+  fn removing_takes_one_from_the_stack() {
+    let mut inventory: Inventory = vec![Item::new("copper_coin", 2)];
+    inventory.remove_item(&Item::new("copper_coin", 1));
+    assert_eq!(inventory[0].qty, 1);
+    inventory.remove_item(&Item::new("copper_coin", 1));
+    assert!(inventory.is_empty());
+  }
+}
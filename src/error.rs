@@ -0,0 +1,164 @@
+/**
+ * Errors returned by the API. Every error is sent as a JSON body of the
+ * form `{"succeeded": false, "err": "<message>", "code": "<code>"}` with
+ * a matching HTTP status, whether it comes from a route or a catcher.
+ */
+use std::fmt;
+use std::io;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, content, Responder, Response};
+
+#[derive(Debug)]
+pub enum MudnixError {
+  InvalidCredentials,
+  NotLoggedIn,
+  Forbidden,
+  UserExists(String),
+  UserNotFound(String),
+  RecipientNotHere(String),
+  MessageTooLong(usize),
+  RateLimited,
+  Muted(u64),
+  InvalidLocation(String),
+  NotAdjacent { from: String, to: String },
+  NoExit(String),
+  InvalidData(Vec<String>),
+  ExitBlocked(String),
+  BadRequest(String),
+  InvalidParameters,
+  NotFound,
+  Storage(String)
+}
+
+pub type JsonResult = Result<content::Json<String>, MudnixError>;
+
+impl MudnixError {
+  pub fn status(&self) -> Status {
+    match self {
+      MudnixError::InvalidCredentials | MudnixError::NotLoggedIn => Status::Unauthorized,
+      MudnixError::Forbidden
+      | MudnixError::Muted(_)
+      | MudnixError::ExitBlocked(_) => Status::Forbidden,
+      MudnixError::RateLimited => Status::TooManyRequests,
+      MudnixError::UserExists(_) => Status::Conflict,
+      MudnixError::UserNotFound(_) | MudnixError::NotFound => Status::NotFound,
+      MudnixError::InvalidLocation(_)
+      | MudnixError::NotAdjacent { .. }
+      | MudnixError::NoExit(_)
+      | MudnixError::InvalidData(_)
+      | MudnixError::RecipientNotHere(_)
+      | MudnixError::MessageTooLong(_)
+      | MudnixError::BadRequest(_) => Status::BadRequest,
+      MudnixError::InvalidParameters => Status::UnprocessableEntity,
+      MudnixError::Storage(_) => Status::InternalServerError
+    }
+  }
+
+  // machine-readable name of the error, for clients
+  pub fn code(&self) -> &'static str {
+    match self {
+      MudnixError::InvalidCredentials => "invalid_credentials",
+      MudnixError::NotLoggedIn => "not_logged_in",
+      MudnixError::Forbidden => "forbidden",
+      MudnixError::UserExists(_) => "user_exists",
+      MudnixError::UserNotFound(_) => "user_not_found",
+      MudnixError::RecipientNotHere(_) => "recipient_not_here",
+      MudnixError::MessageTooLong(_) => "message_too_long",
+      MudnixError::RateLimited => "rate_limited",
+      MudnixError::Muted(_) => "muted",
+      MudnixError::InvalidLocation(_) => "invalid_location",
+      MudnixError::NotAdjacent { .. } => "not_adjacent",
+      MudnixError::NoExit(_) => "no_exit",
+      MudnixError::InvalidData(_) => "invalid_data",
+      MudnixError::ExitBlocked(_) => "exit_blocked",
+      MudnixError::BadRequest(_) => "bad_request",
+      MudnixError::InvalidParameters => "invalid_parameters",
+      MudnixError::NotFound => "not_found",
+      MudnixError::Storage(_) => "storage_error"
+    }
+  }
+
+  pub fn to_json(&self) -> serde_json::Value {
+    serde_json::json!({
+      "succeeded": false,
+      "err": self.to_string(),
+      "code": self.code()
+    })
+  }
+}
+
+impl fmt::Display for MudnixError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MudnixError::InvalidCredentials => write!(f, "invalid credentials"),
+      MudnixError::NotLoggedIn => write!(f, "You are not logged in."),
+      MudnixError::Forbidden => write!(f, "you do not have permission to use this command"),
+      MudnixError::UserExists(username) => write!(f, "User {} already exists.", username),
+      MudnixError::UserNotFound(username) => write!(f, "user {} does not exist", username),
+      MudnixError::RecipientNotHere(username) => write!(f, "{} is not here", username),
+      MudnixError::MessageTooLong(max) => {
+        write!(f, "messages can't be longer than {} characters", max)
+      },
+      MudnixError::RateLimited => write!(f, "you are sending messages too quickly"),
+      MudnixError::Muted(until) => {
+        write!(f, "you have been muted by a moderator until {} (Unix time)", until)
+      },
+      MudnixError::InvalidLocation(info) => write!(f, "{}", info),
+      MudnixError::NotAdjacent { from, to } => write!(f, "{} is not next to {}", from, to),
+      MudnixError::NoExit(direction) => write!(f, "you can't go {} from here", direction),
+      MudnixError::ExitBlocked(reason) => write!(f, "{}", reason),
+      MudnixError::InvalidData(problems) => write!(f, "invalid game data: {}", problems.join("; ")),
+      MudnixError::BadRequest(info) => write!(f, "{}", info),
+      MudnixError::InvalidParameters => write!(f, "missing or invalid parameters"),
+      MudnixError::NotFound => write!(f, "not found"),
+      MudnixError::Storage(info) => write!(f, "storage error: {}", info)
+    }
+  }
+}
+
+impl From<io::Error> for MudnixError {
+  fn from(e: io::Error) -> Self {
+    MudnixError::Storage(e.to_string())
+  }
+}
+
+impl<'r> Responder<'r, 'static> for MudnixError {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    Response::build_from(content::Json(self.to_json().to_string()).respond_to(request)?)
+      .status(self.status())
+      .ok()
+  }
+}
+
+// errors raised before a route runs, e.g. by a failing request guard
+
+#[catch(400)]
+pub fn bad_request() -> MudnixError {
+  MudnixError::BadRequest(String::from("malformed request"))
+}
+
+#[catch(401)]
+pub fn unauthorized() -> MudnixError {
+  MudnixError::NotLoggedIn
+}
+
+#[catch(403)]
+pub fn forbidden() -> MudnixError {
+  MudnixError::Forbidden
+}
+
+#[catch(404)]
+pub fn not_found() -> MudnixError {
+  MudnixError::NotFound
+}
+
+#[catch(422)]
+pub fn unprocessable_entity() -> MudnixError {
+  MudnixError::InvalidParameters
+}
+
+#[catch(500)]
+pub fn internal_error() -> MudnixError {
+  MudnixError::Storage(String::from("internal server error"))
+}
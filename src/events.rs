@@ -0,0 +1,66 @@
+/**
+ * In-process broadcast bus for things that happen in the game. Game
+ * actions publish events here and every connected WebSocket client
+ * receives the ones that are relevant to its player.
+ */
+use rocket::tokio::sync::broadcast;
+use serde::Serialize;
+
+use crate::entities;
+use crate::message;
+
+// how many events a slow subscriber may fall behind before it skips ahead
+const BUS_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GameEvent {
+  Chat { message: message::Message },
+  Arrival { username: String, location_id: String },
+  Departure { username: String, location_id: String },
+  ChestSpawn { username: String, chest: entities::TreasureChest }
+}
+
+impl GameEvent {
+  /* Whether a player standing at `location_id` should be told about this
+     event. `chat_scopes` is everything they hear, from `ChatBus::scopes_of`. */
+  pub fn is_visible_to(
+    &self,
+    username: &str,
+    location_id: &str,
+    chat_scopes: &[message::ChatScope]
+  ) -> bool {
+    match self {
+      GameEvent::Chat { message } => {
+        message::ChatScope::of(message).iter().any(|scope| chat_scopes.contains(scope))
+      },
+      GameEvent::Arrival { username: other, location_id: at }
+      | GameEvent::Departure { username: other, location_id: at } => {
+        other != username && at == location_id
+      },
+      GameEvent::ChestSpawn { username: owner, .. } => owner == username
+    }
+  }
+}
+
+// clones publish to the same bus
+#[derive(Clone)]
+pub struct EventBus {
+  sender: broadcast::Sender<GameEvent>
+}
+
+impl EventBus {
+  pub fn new() -> Self {
+    let (sender, _) = broadcast::channel(BUS_CAPACITY);
+    Self { sender }
+  }
+
+  pub fn publish(&self, event: GameEvent) {
+    // sending only fails when nobody is listening, which is fine
+    let _ = self.sender.send(event);
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<GameEvent> {
+    self.sender.subscribe()
+  }
+}
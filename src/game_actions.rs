@@ -0,0 +1,860 @@
+/**
+ * Game actions shared by the REST endpoints and the WebSocket game channel.
+ * Each returns the JSON body that is sent back to the player, and publishes
+ * whatever other players need to hear about on the event bus.
+ */
+use crate::entities::{self, ItemContainer};
+use crate::events::{EventBus, GameEvent};
+use crate::message;
+use crate::mudnix_utils;
+use crate::user::User;
+use crate::user_store;
+use crate::world_map;
+use crate::error::MudnixError;
+
+/* Everyone who sends a chat message goes through here first: muted users
+   and messages that are too long or too frequent are turned away. */
+fn speaker(
+  username: &str,
+  text: &str,
+  users: &user_store::Users,
+  chat: &message::ChatBus
+) -> Result<User, MudnixError> {
+  let user = mudnix_utils::touch_user(users, username)?;
+  if user.is_muted() {
+    return Err(MudnixError::Muted(user.muted_until.unwrap_or_default()));
+  }
+  chat.check_limits(username, text)?;
+  Ok(user)
+}
+
+// puts a message about something that happened into the location's chat
+pub fn announce(
+  kind: message::MessageKind,
+  text: &str,
+  username: &str,
+  location_id: &str,
+  chat: &message::ChatBus,
+  events: &EventBus
+) {
+  let message = chat.publish(message::Message::of_kind(kind, text, username, location_id));
+  events.publish(GameEvent::Chat { message });
+}
+
+/* Puts the user at the new location and lets the players at both ends
+   know about it. Returns the description of the new location. */
+fn relocate(
+  username: &str,
+  old_location_id: &str,
+  new_location_id: &str,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<String, MudnixError> {
+  let response = world.place_user(username, new_location_id)?;
+  announce_move(username, old_location_id, new_location_id, chat, events);
+  Ok(response)
+}
+
+// lets the players at both ends of a move know about it
+fn announce_move(
+  username: &str,
+  old_location_id: &str,
+  new_location_id: &str,
+  chat: &message::ChatBus,
+  events: &EventBus
+) {
+  if old_location_id != new_location_id {
+    events.publish(GameEvent::Departure {
+      username: String::from(username),
+      location_id: String::from(old_location_id)
+    });
+    announce(
+      message::MessageKind::Departure,
+      &format!(
+        "{} leaves for {}.",
+        username,
+        world_map::location_id_to_human_readable(new_location_id)
+      ),
+      username, old_location_id, chat, events
+    );
+    events.publish(GameEvent::Arrival {
+      username: String::from(username),
+      location_id: String::from(new_location_id)
+    });
+    announce(
+      message::MessageKind::Arrival,
+      &format!(
+        "{} arrives from {}.",
+        username,
+        world_map::location_id_to_human_readable(old_location_id)
+      ),
+      username, new_location_id, chat, events
+    );
+  }
+}
+
+// moves the user anywhere in the world, without checking that it is adjacent
+pub fn teleport(
+  username: &str,
+  new_location_id: &str,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let old_location_id = mudnix_utils::get_user(users, username)?.world_location;
+  let response = relocate(username, &old_location_id, new_location_id, world, chat, events)?;
+  let user = mudnix_utils::update_user(users, username, &mut |user| {
+    user.world_location = String::from(new_location_id);
+    user.update_timestamp();
+  })?;
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": response,
+    "active_treasure_chest": user.active_treasure_chest
+  }))
+}
+
+/* Whether the user may walk from where they are to `new_location_id`: it
+   must be in the same WorldLocation or a neighbor, and if an exit leads
+   there, the user must meet its conditions. A sublocation that a gated exit
+   leads to can't be walked to from elsewhere in its WorldLocation, only
+   through its exits and neighbors. */
+pub fn check_move(
+  user: &User,
+  new_location_id: &str,
+  world: &world_map::World
+) -> Result<(), MudnixError> {
+  let old_location_id: &str = &user.world_location;
+  let old_sublocation_id = world_map::get_sublocation_from_id(old_location_id)?;
+  let adjacent = world.adjacent_to(old_location_id);
+
+  let (legal_to_move, refusal) = world.with_location(old_location_id, |old_location| {
+    let old_sublocation = old_location.attrs.sublocations.iter()
+      .find(|sl| sl.name == old_sublocation_id);
+    let legal_to_move = old_location_id == new_location_id
+      || adjacent.iter().any(|location_id| location_id == new_location_id);
+    // an exit's conditions apply even where the user could walk without it
+    let checks: Vec<Result<(), String>> = old_sublocation.into_iter()
+      .flat_map(|sl| sl.exits.iter())
+      .filter(|exit| exit.to == new_location_id)
+      .map(|exit| exit.check(user))
+      .collect();
+    let refusal = if checks.iter().any(|check| check.is_ok()) {
+      None
+    } else {
+      checks.into_iter().find_map(|check| check.err())
+    };
+    (legal_to_move, refusal)
+  })?;
+
+  if !legal_to_move {
+    return Err(MudnixError::NotAdjacent {
+      from: world_map::location_id_to_human_readable(old_location_id),
+      to: world_map::location_id_to_human_readable(new_location_id)
+    });
+  }
+  match refusal {
+    Some(refusal) => Err(MudnixError::ExitBlocked(refusal)),
+    None => Ok(())
+  }
+}
+
+// the user's open treasure chest with its contents described, or null
+fn chest_json(
+  chest: &Option<entities::TreasureChest>,
+  items: &entities::ItemRegistry
+) -> serde_json::Value {
+  match chest {
+    Some(chest) => serde_json::json!({ "contents": items.describe_all(&chest.contents) }),
+    None => serde_json::Value::Null
+  }
+}
+
+// walks the user to a neighboring location, possibly spawning a treasure chest
+pub fn move_to(
+  username: &str,
+  new_location_id: &str,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus,
+  items: &entities::ItemRegistry
+) -> Result<serde_json::Value, MudnixError> {
+  step(username, new_location_id, true, users, world, chat, events, items)
+}
+
+/* Like `move_to`, but for a location the user is only passing through on
+   the way somewhere else, where no treasure chest spawns, since they'd
+   have walked on before they could open it. */
+pub fn move_through(
+  username: &str,
+  new_location_id: &str,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus,
+  items: &entities::ItemRegistry
+) -> Result<serde_json::Value, MudnixError> {
+  step(username, new_location_id, false, users, world, chat, events, items)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn step(
+  username: &str,
+  new_location_id: &str,
+  may_spawn_chest: bool,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus,
+  items: &entities::ItemRegistry
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::get_user(users, username)?;
+  let old_location_id: &str = &user.world_location;
+  check_move(&user, new_location_id, world)?;
+
+  let response = relocate(username, old_location_id, new_location_id, world, chat, events)?;
+  let (treasure_chest_spawn_rate, biome) = world.with_location(
+    new_location_id,
+    |new_location| (new_location.attrs.treasure_chest_spawn_rate, new_location.attrs.biome.clone())
+  )?;
+
+  // generate a TreasureChest
+  let active_treasure_chest = if may_spawn_chest {
+    items.roll_chest(new_location_id, &biome, treasure_chest_spawn_rate)
+  } else {
+    None
+  };
+  let user = mudnix_utils::update_user(users, username, &mut |user| {
+    user.active_treasure_chest = active_treasure_chest.clone();
+    user.world_location = String::from(new_location_id);
+    user.update_timestamp();
+  })?;
+  if let Some(chest) = &user.active_treasure_chest {
+    events.publish(GameEvent::ChestSpawn {
+      username: String::from(username),
+      chest: chest.clone()
+    });
+    announce(
+      message::MessageKind::ItemDrop,
+      &format!("A treasure chest appears in front of {}.", username),
+      username, new_location_id, chat, events
+    );
+  }
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": response,
+    "active_treasure_chest": chest_json(&user.active_treasure_chest, items)
+  }))
+}
+
+// walks the user out of their sublocation in the given direction
+pub fn move_direction(
+  username: &str,
+  direction: world_map::Direction,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus,
+  items: &entities::ItemRegistry
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::get_user(users, username)?;
+  let sublocation_id = world_map::get_sublocation_from_id(&user.world_location)?;
+  let destination = world.with_location(&user.world_location, |location| {
+    let i = location.sublocation_index(&sublocation_id)?;
+    Ok::<Option<String>, MudnixError>(
+      location.attrs.sublocations[i].exit(direction).map(|exit| exit.to.clone())
+    )
+  })??;
+  match destination {
+    Some(destination) => move_to(username, &destination, users, world, chat, events, items),
+    None => Err(MudnixError::NoExit(direction.to_string()))
+  }
+}
+
+/* Opens, closes, locks or unlocks the door across the exit in the given
+   direction, and the matching door on the other side. */
+pub fn door(
+  username: &str,
+  direction: world_map::Direction,
+  action: world_map::DoorAction,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::touch_user(users, username)?;
+  let location_id: &str = &user.world_location;
+  let (door, _) = world.use_door(location_id, direction, |door| door.apply(action, &user))?;
+
+  let verb = match action {
+    world_map::DoorAction::Open => "opens",
+    world_map::DoorAction::Close => "closes",
+    world_map::DoorAction::Lock => "locks",
+    world_map::DoorAction::Unlock => "unlocks"
+  };
+  announce(
+    message::MessageKind::System,
+    &format!("{} {} the {} to the {}.", username, verb, door.name, direction),
+    username, location_id, chat, events
+  );
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": format!("You {} the {}.", verb.trim_end_matches('s'), door.name),
+    "door": door
+  }))
+}
+
+// a place the user can go, as shown to players
+fn exit_json(location_id: &str, label: Option<&str>) -> serde_json::Value {
+  serde_json::json!({
+    "to": location_id,
+    "name": world_map::location_id_to_human_readable(location_id),
+    "label": label
+  })
+}
+
+/* The locations the user can move to from where they are standing, both as
+   a flat list of IDs and grouped by direction. Places with no direction,
+   like the other sublocations of the same WorldLocation, are under "other". */
+pub fn exits(
+  username: &str,
+  users: &user_store::Users,
+  world: &world_map::World
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::get_user(users, username)?;
+  let old_location_id: &str = &user.world_location;
+  let old_sublocation_id = world_map::get_sublocation_from_id(old_location_id)?;
+  let adjacent = world.adjacent_to(old_location_id);
+  let (neighbors, grouped) = world.with_location(old_location_id, |old_location| {
+    let mut neighbors: Vec<String> = vec![];
+    let mut grouped = serde_json::Map::new();
+    let old_sublocation = &old_location.attrs.sublocations[
+      old_location.sublocation_index(&old_sublocation_id)?
+    ];
+    for exit in old_sublocation.exits.iter() {
+      let by_direction = grouped.entry(exit.direction.to_string())
+        .or_insert_with(|| serde_json::json!([]));
+      if let Some(by_direction) = by_direction.as_array_mut() {
+        by_direction.push(exit_json(&exit.to, exit.label.as_deref()));
+      }
+    }
+    for neighbor in old_sublocation.neighbors.iter() {
+      neighbors.push(String::from(neighbor));
+    }
+    // leaving out those with a gated entrance, which can't be walked to directly
+    for sublocation in old_location.attrs.sublocations.iter() {
+      let location_id = format!("{}::{}", old_location.name, sublocation.name);
+      if adjacent.contains(&location_id) {
+        neighbors.push(location_id);
+      }
+    }
+    let other: Vec<serde_json::Value> = neighbors.iter()
+      .filter(|neighbor| neighbor.as_str() != old_location_id)
+      .filter(|neighbor| !old_sublocation.exits.iter().any(|exit| &exit.to == *neighbor))
+      .map(|neighbor| exit_json(neighbor, None))
+      .collect();
+    grouped.insert(String::from("other"), serde_json::json!(other));
+    Ok::<(Vec<String>, serde_json::Map<String, serde_json::Value>), MudnixError>(
+      (neighbors, grouped)
+    )
+  })??;
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "locations": neighbors,
+    "exits": grouped
+  }))
+}
+
+/* Where the user is and what it looks like right now, who else is there,
+   where they can go and what is lying around. `info` puts it all into a
+   few lines of text for clients that just print it. */
+pub fn look(
+  username: &str,
+  users: &user_store::Users,
+  world: &world_map::World,
+  items: &entities::ItemRegistry
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::touch_user(users, username)?;
+  let location_id: &str = &user.world_location;
+  let sublocation_id = world_map::get_sublocation_from_id(location_id)?;
+  let exits = exits(username, users, world)?;
+  let time_of_day = world_map::TimeOfDay::now();
+
+  let (description, npcs, ground) = world.with_location(location_id, |location| {
+    let sublocation = &location.attrs.sublocations[location.sublocation_index(&sublocation_id)?];
+    let description: Vec<&str> = [
+      location.attrs.description.render(time_of_day, &location.attrs.biome),
+      sublocation.description.render(time_of_day, &location.attrs.biome)
+    ].into_iter().filter(|text| !text.is_empty()).collect();
+    let npcs: Vec<String> = sublocation.npcs.iter().map(|npc| npc.name.clone()).collect();
+    Ok::<(String, Vec<String>, entities::Inventory), MudnixError>(
+      (description.join(" "), npcs, sublocation.items.clone())
+    )
+  })??;
+  let others: Vec<String> = world.users_at(location_id)?.into_iter()
+    .filter(|other| other != username)
+    .collect();
+
+  let mut info = vec![format!(
+    "You are in {}.",
+    world_map::location_id_to_human_readable(location_id)
+  )];
+  if !description.is_empty() {
+    info.push(description.clone());
+  }
+  let directions: Vec<String> = exits["exits"].as_object().into_iter().flatten()
+    .filter(|(direction, _)| direction.as_str() != "other")
+    .flat_map(|(direction, exits)| exits.as_array().into_iter().flatten().map(move |exit| {
+      format!(
+        "{} ({})",
+        direction,
+        exit["label"].as_str().or(exit["name"].as_str()).unwrap_or_default()
+      )
+    }))
+    .collect();
+  if !directions.is_empty() {
+    info.push(format!("Exits: {}.", directions.join(", ")));
+  }
+  if !others.is_empty() || !npcs.is_empty() {
+    info.push(format!("Also here: {}.", [others.clone(), npcs.clone()].concat().join(", ")));
+  }
+  if !ground.is_empty() {
+    let names: Vec<String> = ground.iter()
+      .map(|item| {
+        let name = items.name_of(item);
+        if item.qty > 1 { format!("{} ({})", name, item.qty) } else { name }
+      })
+      .collect();
+    info.push(format!("On the ground: {}.", names.join(", ")));
+  }
+
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "active_location": location_id,
+    "name": world_map::location_id_to_human_readable(location_id),
+    "time_of_day": time_of_day,
+    "description": description,
+    "info": info.join("\n"),
+    "nearby_users": world.users_at(location_id)?,
+    "npcs": npcs,
+    "items": items.describe_all(&ground),
+    "locations": exits["locations"],
+    "exits": exits["exits"],
+    "active_treasure_chest": chest_json(&user.active_treasure_chest, items)
+  }))
+}
+
+// moves the contents of the user's open treasure chest into their inventory
+pub fn take(
+  username: &str,
+  users: &user_store::Users,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let mut had_chest = false;
+  let user = mudnix_utils::update_user(users, username, &mut |user| {
+    if let Some(treasure_chest) = user.active_treasure_chest.take() {
+      had_chest = true;
+      for item in treasure_chest.contents.iter() {
+        user.inventory.add_item(item);
+      }
+    }
+    user.update_timestamp();
+  })?;
+  if had_chest {
+    announce(
+      message::MessageKind::System,
+      &format!("{} empties a treasure chest, which vanishes in green flames.", username),
+      username, &user.world_location, chat, events
+    );
+  }
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": "The chest closes and disappears into ethereal green flames\
+            \nwhich radiate no heat."
+  }))
+}
+
+/* Says something to everyone at the user's location, or on a channel:
+   `global`, `region` (the WorldLocation the user is in) or one they've joined. */
+pub fn say(
+  username: &str,
+  text: &str,
+  channel: Option<&str>,
+  users: &user_store::Users,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = speaker(username, text, users, chat)?;
+  let message = match channel {
+    // "/me waves" is the same as emoting "waves"
+    None => match text.strip_prefix("/me ") {
+      Some(action) => message::Message::of_kind(
+        message::MessageKind::Emote, action, username, &user.world_location
+      ),
+      None => message::Message::new(text, username, &user.world_location)
+    },
+    Some(channel) => {
+      let channel = match channel {
+        message::GLOBAL_CHANNEL => String::from(message::GLOBAL_CHANNEL),
+        "region" => message::region_channel_of(&user.world_location),
+        _ if chat.is_member(username, channel) => String::from(channel),
+        _ => return Err(MudnixError::BadRequest(format!(
+          "you are not on the {} channel",
+          channel
+        )))
+      };
+      message::Message::on_channel(text, username, &user.world_location, &channel)
+    }
+  };
+  let message = chat.publish(message);
+  let id = message.id;
+  events.publish(GameEvent::Chat { message });
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "id": id
+  }))
+}
+
+// an action, like "waves", seen by everyone at the user's location
+pub fn emote(
+  username: &str,
+  action: &str,
+  users: &user_store::Users,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = speaker(username, action, users, chat)?;
+  let message = chat.publish(message::Message::of_kind(
+    message::MessageKind::Emote, action, username, &user.world_location
+  ));
+  let id = message.id;
+  events.publish(GameEvent::Chat { message });
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "id": id
+  }))
+}
+
+// a private message to someone standing at the same location
+pub fn whisper(
+  username: &str,
+  recipient: &str,
+  text: &str,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = speaker(username, text, users, chat)?;
+  if world.location_of(recipient).as_ref() != Some(&user.world_location) {
+    return Err(MudnixError::RecipientNotHere(String::from(recipient)));
+  }
+  let message = chat.publish(message::Message::direct(
+    text, username, &user.world_location, recipient
+  ));
+  let id = message.id;
+  events.publish(GameEvent::Chat { message });
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "id": id
+  }))
+}
+
+/* A private message to anyone, wherever they are. If the recipient is
+   offline it goes in their mailbox and they get it when they next log in. */
+pub fn tell(
+  username: &str,
+  recipient: &str,
+  text: &str,
+  users: &user_store::Users,
+  logged_in_user_pool: &mudnix_utils::LoggedInUserPool,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = speaker(username, text, users, chat)?;
+  let message = message::Message::direct(text, username, &user.world_location, recipient);
+
+  if logged_in_user_pool.contains(recipient) {
+    let message = chat.publish(message);
+    let id = message.id;
+    events.publish(GameEvent::Chat { message });
+    Ok(serde_json::json!({
+      "username": username,
+      "succeeded": true,
+      "id": id,
+      "delivered": true
+    }))
+  } else {
+    mudnix_utils::update_user(users, recipient, &mut |user| {
+      if !user.is_ignoring(username) {
+        user.mailbox.push(message.clone());
+      }
+    })?;
+    Ok(serde_json::json!({
+      "username": username,
+      "succeeded": true,
+      "delivered": false,
+      "info": format!("{} is offline and will get your message when they next log in.", recipient)
+    }))
+  }
+}
+
+/* Takes the user into a freshly generated instance, and invites the
+   companions standing with them to follow. They come back out the way they
+   came in. */
+pub fn enter_instance(
+  username: &str,
+  companions: &[String],
+  instance: world_map::WorldLocation,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::touch_user(users, username)?;
+  /* the way back out of an instance leads to where it was started from,
+     so starting one from inside another would strand the party once the
+     outer instance is torn down */
+  if world.instance_origin(&user.world_location).is_some() {
+    return Err(MudnixError::BadRequest(String::from(
+      "you can't start an instance from inside one"
+    )));
+  }
+  for companion in companions.iter() {
+    if world.location_of(companion).as_ref() != Some(&user.world_location) {
+      return Err(MudnixError::RecipientNotHere(String::from(companion)));
+    }
+  }
+  let name = instance.name.clone();
+  let entrance = format!("{}::{}", name, instance.attrs.sublocations[0].name);
+  let response = world.add_instance(instance, username)?;
+  arrive(username, &user.world_location, &entrance, users, chat, events)?;
+
+  for companion in companions.iter() {
+    let message = chat.publish(message::Message {
+      kind: message::MessageKind::System,
+      ..message::Message::direct(
+        &format!(
+          "{} invites you into {}. Accept the invitation to follow them in.",
+          username, name
+        ),
+        username, &user.world_location, companion
+      )
+    });
+    events.publish(GameEvent::Chat { message });
+  }
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": response,
+    "instance": name,
+    "active_location": entrance,
+    "invited": companions
+  }))
+}
+
+/* Follows the party into an instance the user has been invited into. They
+   must still be where the party set off from. */
+pub fn accept_invitation(
+  username: &str,
+  instance: &str,
+  users: &user_store::Users,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::touch_user(users, username)?;
+  let origin = world.instance_origin(instance)
+    .ok_or_else(|| MudnixError::BadRequest(format!("you have not been invited into {}", instance)))?;
+  if origin != user.world_location {
+    return Err(MudnixError::BadRequest(format!(
+      "you must be at {} to follow the party in",
+      world_map::location_id_to_human_readable(&origin)
+    )));
+  }
+  let (entrance, response) = world.join_instance(username, instance)?;
+  arrive(username, &user.world_location, &entrance, users, chat, events)?;
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": response,
+    "instance": instance,
+    "active_location": entrance
+  }))
+}
+
+pub fn decline_invitation(
+  username: &str,
+  instance: &str,
+  world: &world_map::World
+) -> Result<serde_json::Value, MudnixError> {
+  if !world.decline_instance(username, instance) {
+    return Err(MudnixError::BadRequest(format!("you have not been invited into {}", instance)));
+  }
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": format!("You decline the invitation into {}.", instance)
+  }))
+}
+
+// records that the World has put the user somewhere new, and lets everyone know
+fn arrive(
+  username: &str,
+  old_location_id: &str,
+  new_location_id: &str,
+  users: &user_store::Users,
+  chat: &message::ChatBus,
+  events: &EventBus
+) -> Result<(), MudnixError> {
+  announce_move(username, old_location_id, new_location_id, chat, events);
+  mudnix_utils::update_user(users, username, &mut |user| {
+    user.world_location = String::from(new_location_id);
+    user.update_timestamp();
+  })?;
+  Ok(())
+}
+
+// joins a player-created channel, creating it if nobody is on it yet
+pub fn join_channel(
+  username: &str,
+  channel: &str,
+  users: &user_store::Users,
+  chat: &message::ChatBus
+) -> Result<serde_json::Value, MudnixError> {
+  if !message::is_valid_channel_name(channel) {
+    return Err(MudnixError::BadRequest(format!(
+      "{} is not a valid channel name; use up to 32 letters, digits, - and _",
+      channel
+    )));
+  }
+  // channels are remembered on the user so they are rejoined on login
+  mudnix_utils::update_user(users, username, &mut |user| {
+    if !user.channels.iter().any(|c| c == channel) {
+      user.channels.push(String::from(channel));
+    }
+    user.update_timestamp();
+  })?;
+  let created = chat.join(username, channel);
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": if created {
+      format!("You created the {} channel.", channel)
+    } else {
+      format!("You joined the {} channel.", channel)
+    }
+  }))
+}
+
+pub fn leave_channel(
+  username: &str,
+  channel: &str,
+  users: &user_store::Users,
+  chat: &message::ChatBus
+) -> Result<serde_json::Value, MudnixError> {
+  if !chat.is_member(username, channel) {
+    return Err(MudnixError::BadRequest(format!(
+      "you are not on the {} channel",
+      channel
+    )));
+  }
+  mudnix_utils::update_user(users, username, &mut |user| {
+    user.channels.retain(|c| c != channel);
+    user.update_timestamp();
+  })?;
+  chat.leave(username, channel);
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": format!("You left the {} channel.", channel)
+  }))
+}
+
+pub fn list_channels(
+  username: &str,
+  users: &user_store::Users,
+  chat: &message::ChatBus
+) -> Result<serde_json::Value, MudnixError> {
+  let user = mudnix_utils::touch_user(users, username)?;
+  let channels: Vec<serde_json::Value> = chat.list().iter().map(|(channel, members)| {
+    serde_json::json!({
+      "name": channel,
+      "members": members,
+      "joined": chat.is_member(username, channel)
+    })
+  }).collect();
+  Ok(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "built_in": [message::GLOBAL_CHANNEL, message::region_channel_of(&user.world_location)],
+    "channels": channels
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::world_map::tests::house;
+  use crate::worldgen;
+
+  #[test]
+  fn locked_door_cant_be_walked_around() {
+    let world = house();
+    let mut user = User::new("alice", "", "House::kitchen");
+    assert!(check_move(&user, "House::hall", &world).is_ok());
+    assert!(matches!(
+      check_move(&user, "House::closet", &world),
+      Err(MudnixError::NotAdjacent { .. })
+    ));
+    user.world_location = String::from("House::hall");
+    assert!(matches!(
+      check_move(&user, "House::closet", &world),
+      Err(MudnixError::ExitBlocked(reason)) if reason == "The door is closed."
+    ));
+
+    world.use_door("House::hall", world_map::Direction::North, |door| {
+      door.locked = false;
+      door.open = true;
+      Ok(())
+    }).unwrap();
+    assert!(check_move(&user, "House::closet", &world).is_ok());
+  }
+
+  #[test]
+  fn no_instance_inside_an_instance() {
+    let world = house();
+    let users: user_store::Users = std::sync::Arc::new(
+      user_store::SqliteUserStore::open(":memory:").unwrap()
+    );
+    users.insert(&User::new("alice", "", "House::hall")).unwrap();
+    let chat = message::ChatBus::new(&crate::config::MudnixConfig::default());
+    let events = EventBus::new();
+    let plains = || world_map::Biome { eco: String::from("plains"), urban: false };
+
+    let instance = worldgen::generate_instance(1, plains(), 3, vec![], "House::hall");
+    enter_instance("alice", &[], instance, &users, &world, &chat, &events).unwrap();
+    let inner = world.location_of("alice").unwrap();
+    assert_eq!(world.instance_origin(&inner).as_deref(), Some("House::hall"));
+
+    let instance = worldgen::generate_instance(2, plains(), 3, vec![], &inner);
+    assert!(matches!(
+      enter_instance("alice", &[], instance, &users, &world, &chat, &events),
+      Err(MudnixError::BadRequest(_))
+    ));
+    assert_eq!(world.location_of("alice"), Some(inner));
+  }
+}
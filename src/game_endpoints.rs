@@ -1,341 +1,527 @@
 /**
  * API endpoints for things that happen in the game, or game actions
- * that a user may take.
+ * that a user may take. The actions themselves live in `game_actions`
+ * so that the WebSocket game channel can share them.
  */
-use crate::rand::Rng;
 use rocket::State;
 use rocket::response::content;
+use rocket::futures::stream::{Stream, StreamExt};
 use rocket::response::stream::{Event, EventStream};
+use rocket::tokio;
 use rocket::tokio::time::{self, Duration};
 use serde_json;
 
-use crate::entities::ItemContainer;
-use crate::entities;
-use crate::user;
+use crate::user_store;
 use crate::world_map;
 use crate::mudnix_utils;
+use crate::config;
 use crate::message;
+use crate::session;
+use crate::events;
+use crate::game_actions;
+use crate::chat_log;
+use crate::travel;
+use crate::worldgen;
+use crate::world_builder;
+use crate::entities;
+use crate::loot;
+use crate::error::{MudnixError, JsonResult};
 
-#[get("/tp?<username>&<password>&<new_location>")]
+#[post("/tp?<new_location>")]
 pub fn teleport(
-  username: &str,
-  password: &str,
+  player: session::Authorized<session::CanTeleport>,
   new_location: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-  let correct_hash = "e6fd95a315bb7129a50fd85b20af443d9a4d42c22aaff632c81808b4aee53335";
-  if username == "dante_falzone" && mudnix_utils::hash(password) == correct_hash {
-    mudnix_utils::move_user(username, correct_hash, new_location, users_file_path, &mut user_list)
-  } else {
-    mudnix_utils::error_response(username, "you do not have permission to use this command")
-  }
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let response = game_actions::teleport(
+    &player.user.username, new_location, users, world, chat, events
+  )?;
+  Ok(content::Json(response.to_string()))
 }
 
-#[get("/goto?<username>&<password>&<new_location_id>")]
+#[post("/goto?<new_location_id>")]
 pub fn goto(
-  username: &str,
-  password: &str,
+  player: session::AuthenticatedUser,
   new_location_id: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-  let password_hash = mudnix_utils::hash(password);
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    let old_location_id: &str = &user_list.users[i].world_location;
-    let mut old_location = match world_map::WorldLocation::from_location_id(old_location_id) {
-      Ok(current_location) => current_location,
-      Err(_) => return mudnix_utils::error_response(
-        username, &format!(
-          "cannot move you from invalid location \"{}\"",
-          old_location_id
-        )
-      )
-    };
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>,
+  items: &State<entities::ItemRegistry>
+) -> JsonResult {
+  let response = game_actions::move_to(
+    &player.username, new_location_id, users, world, chat, events, items
+  )?;
+  Ok(content::Json(response.to_string()))
+}
 
-    let old_sublocation_id = match world_map::get_sublocation_from_id(old_location_id) {
-      Ok(id) => id,
-      Err(_) => return mudnix_utils::error_response(
-        username, &format!(
-          "no sublocation specified for {}",
-          old_location_id
-        )
-      )
-    };
+#[post("/move?<dir>")]
+pub fn move_direction(
+  player: session::AuthenticatedUser,
+  dir: world_map::Direction,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>,
+  items: &State<entities::ItemRegistry>
+) -> JsonResult {
+  let response = game_actions::move_direction(
+    &player.username, dir, users, world, chat, events, items
+  )?;
+  Ok(content::Json(response.to_string()))
+}
 
-    let legal_to_move: bool =
-      old_location_id == new_location_id
-      || old_location.name == world_map::get_parent_location_from_id(new_location_id)
-      || old_location.attrs.sublocations.iter().any(
-        |sl| sl.name == old_sublocation_id
-        && sl.is_neighbor(new_location_id)
-      );
+#[post("/door?<dir>&<action>")]
+pub fn door(
+  player: session::AuthenticatedUser,
+  dir: world_map::Direction,
+  action: world_map::DoorAction,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let response = game_actions::door(&player.username, dir, action, users, world, chat, events)?;
+  Ok(content::Json(response.to_string()))
+}
 
-    if legal_to_move {
-      let response = match old_location.move_user_from(
-        username, old_location_id
-      ).to(new_location_id) {
-        Ok(r) => r,
-        Err(_) => return mudnix_utils::error_response(
-          username, &format!("cannot move you to invalid location {}", new_location_id)
-        )
-      };
-      let new_location = world_map::WorldLocation::from_location_id(new_location_id)
-        .unwrap();
+// walks the user to any location, one step every few seconds
+#[allow(clippy::too_many_arguments)]
+#[post("/travel?<to>")]
+pub fn travel_to(
+  player: session::AuthenticatedUser,
+  to: &str,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>,
+  logged_in_user_pool: &State<mudnix_utils::LoggedInUserPool>,
+  journeys: &State<travel::Journeys>,
+  items: &State<entities::ItemRegistry>
+) -> JsonResult {
+  let walker = travel::Walker {
+    users: users.inner().clone(),
+    world: world.inner().clone(),
+    chat: chat.inner().clone(),
+    events: events.inner().clone(),
+    logged_in_user_pool: logged_in_user_pool.inner().clone(),
+    items: items.inner().clone()
+  };
+  let response = journeys.start(&player.username, to, walker)?;
+  Ok(content::Json(response.to_string()))
+}
 
-      // generate a TreasureChest
-      let spawn_val = rand::thread_rng().gen_range(0.0..1.0);
-      if spawn_val < new_location.attrs.treasure_chest_spawn_rate {
-        user_list.users[i].active_treasure_chest = Some(entities::TreasureChest::new());
-      } else {
-        user_list.users[i].active_treasure_chest = None;
-      }
-      user_list.users[i].world_location = String::from(new_location_id);
-      user_list.update_timestamp_of_index(i);
-      user_list.save_to_file(users_file_path);
-      content::Json(serde_json::json!({
-        "username": username,
-        "succeeded": true,
-        "info": response,
-        "active_treasure_chest": user_list.users[i].active_treasure_chest
-      }).to_string())
-    } else {
-      mudnix_utils::error_response(
-        username,
-        &format!(
-          "{} is not next to {}",
-          world_map::location_id_to_human_readable(old_location_id),
-          world_map::location_id_to_human_readable(new_location_id)
-        )
-      )
-    }
-  } else {
-    mudnix_utils::error_response(username, "invalid credentials")
+#[post("/travel/stop")]
+pub fn stop_travel(
+  player: session::AuthenticatedUser,
+  journeys: &State<travel::Journeys>
+) -> JsonResult {
+  let was_traveling = journeys.stop(&player.username);
+  Ok(content::Json(serde_json::json!({
+    "username": player.username,
+    "succeeded": true,
+    "info": if was_traveling { "You stop walking." } else { "You aren't going anywhere." }
+  }).to_string()))
+}
+
+// the Biome to generate a location with: the given one, or else the user's current one
+fn biome_of(
+  location_id: &str,
+  eco: Option<&str>,
+  urban: Option<bool>,
+  world: &world_map::World
+) -> Result<world_map::Biome, MudnixError> {
+  let current = world.with_location(location_id, |location| location.attrs.biome.clone())?;
+  Ok(world_map::Biome {
+    eco: eco.map_or(current.eco, String::from),
+    urban: urban.unwrap_or(current.urban)
+  })
+}
+
+/* Generates a temporary location, like the user's current one unless
+   `eco` and `urban` are given, and takes the user there. The
+   comma-separated players listed in `with`, who must be in the same place,
+   are invited to follow. */
+#[allow(clippy::too_many_arguments)]
+#[post("/instance?<eco>&<urban>&<seed>&<size>&<with>")]
+pub fn enter_instance(
+  player: session::AuthenticatedUser,
+  eco: Option<&str>,
+  urban: Option<bool>,
+  seed: Option<u64>,
+  size: Option<usize>,
+  with: Option<&str>,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let username: &str = &player.username;
+  let user = mudnix_utils::get_user(users, username)?;
+  let companions: Vec<String> = with.unwrap_or_default().split(',')
+    .map(str::trim)
+    .filter(|companion| !companion.is_empty() && *companion != username)
+    .map(String::from)
+    .collect();
+  let seed = seed.unwrap_or_else(rand::random);
+  let instance = worldgen::generate_instance(
+    seed,
+    biome_of(&user.world_location, eco, urban, world)?,
+    size.unwrap_or(5),
+    companions.clone(),
+    &user.world_location
+  );
+  let mut response = game_actions::enter_instance(
+    username, &companions, instance, users, world, chat, events
+  )?;
+  response["seed"] = serde_json::json!(seed);
+  Ok(content::Json(response.to_string()))
+}
+
+// follows a party into an instance the user has been invited into
+#[post("/instance/accept?<instance>")]
+pub fn accept_invitation(
+  player: session::AuthenticatedUser,
+  instance: &str,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let response = game_actions::accept_invitation(
+    &player.username, instance, users, world, chat, events
+  )?;
+  Ok(content::Json(response.to_string()))
+}
+
+#[post("/instance/decline?<instance>")]
+pub fn decline_invitation(
+  player: session::AuthenticatedUser,
+  instance: &str,
+  world: &State<world_map::World>
+) -> JsonResult {
+  let response = game_actions::decline_invitation(&player.username, instance, world)?;
+  Ok(content::Json(response.to_string()))
+}
+
+// shows builders what the generator makes of a seed, without adding it to the world
+#[allow(clippy::too_many_arguments)]
+#[get("/generate?<name>&<seed>&<eco>&<urban>&<size>")]
+pub fn generate_location(
+  builder: session::Authorized<session::CanBuildWorld>,
+  name: &str,
+  seed: Option<u64>,
+  eco: Option<&str>,
+  urban: Option<bool>,
+  size: Option<usize>,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>
+) -> JsonResult {
+  let user = mudnix_utils::touch_user(users, &builder.user.username)?;
+  let seed = seed.unwrap_or_else(rand::random);
+  let biome = biome_of(&user.world_location, eco, urban, world)?;
+  let location = worldgen::generate(name, seed, biome, size.unwrap_or(5));
+  Ok(content::Json(serde_json::json!({
+    "username": builder.user.username,
+    "succeeded": true,
+    "seed": seed,
+    "location": location
+  }).to_string()))
+}
+
+/* Re-reads the map, item definitions and loot tables and, if they are
+   valid, swaps them in without moving anyone. Problems with the world graph
+   only stop the reload if `world_validation` is "strict". */
+#[post("/reload")]
+pub fn reload(
+  admin: session::Authorized<session::CanReloadData>,
+  world: &State<world_map::World>,
+  world_builder: &State<world_builder::WorldBuilder>,
+  items: &State<entities::ItemRegistry>,
+  config: &State<config::MudnixConfig>
+) -> JsonResult {
+  let (templates, mut problems) = entities::ItemRegistry::read(&config.item_dir);
+  let (loot_tables, loot_problems) = loot::read_tables(&config.loot_dir, &templates);
+  problems.extend(loot_problems);
+  if !problems.is_empty() {
+    return Err(MudnixError::InvalidData(problems));
   }
+  let (location_count, warnings) = world_builder.reload(
+    world, config.world_validation == config::WorldValidation::Strict
+  )?;
+  items.replace(templates, loot_tables);
+  Ok(content::Json(serde_json::json!({
+    "username": admin.user.username,
+    "succeeded": true,
+    "info": format!("reloaded {} locations and {} items", location_count, items.count()),
+    "warnings": warnings
+  }).to_string()))
 }
 
-#[get("/map?<username>&<password>")]
+#[get("/map")]
 pub fn map(
-  username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let user_list = user::UserList::from_file(users_file_path);
-  let password_hash = mudnix_utils::hash(password);
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    let old_location_id: &str = &user_list.users[i].world_location;
-    let old_location = match world_map::WorldLocation::from_location_id(old_location_id) {
-      Ok(current_location) => current_location,
-      Err(_) => return mudnix_utils::error_response(
-        username,
-        &format!(
-          "you are currently located at invalid location \"{}\"",
-          old_location_id
-        )
-      )
-    };
-    let old_sublocation_id = match world_map::get_sublocation_from_id(old_location_id) {
-      Ok(id) => id,
-      Err(_) => return mudnix_utils::error_response(
-        username,
-        &format!(
-          "location id {} does not contain a sublocation",
-          old_location_id
-        )
-      )
-    };
-    let mut neighbors: Vec<String> = vec![];
-    let old_sublocation_index = match old_location.sublocation_index(&old_sublocation_id) {
-      Ok(i) => i,
-      Err(_) => return mudnix_utils::error_response(
-        username,
-        &format!(
-          "unable to find the requested sublocation {}",
-          old_sublocation_id
-        )
-      )
-    };
-    for neighbor in old_location.attrs.sublocations[old_sublocation_index].neighbors.iter() {
-      neighbors.push(String::from(neighbor));
-    }
-    for sublocation in old_location.attrs.sublocations {
-      neighbors.push(format!(
-        "{}::{}",
-        old_location.name,
-        sublocation.name
-      ));
-    }
-    content::Json(serde_json::json!({
-      "username": username,
-      "succeeded": true,
-      "locations": neighbors
-    }).to_string())
-  } else {
-    mudnix_utils::error_response(username, "invalid credentials")
-  }
+  player: session::AuthenticatedUser,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>
+) -> JsonResult {
+  let response = game_actions::exits(&player.username, users, world)?;
+  Ok(content::Json(response.to_string()))
 }
 
-#[get("/close-chest?<username>&<password>")]
+#[get("/look")]
+pub fn look(
+  player: session::AuthenticatedUser,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  items: &State<entities::ItemRegistry>
+) -> JsonResult {
+  let response = game_actions::look(&player.username, users, world, items)?;
+  Ok(content::Json(response.to_string()))
+}
+
+#[post("/close-chest")]
 pub fn close_chest(
-  username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-  let password_hash = mudnix_utils::hash(password);
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    if let Some(treasure_chest) = user_list.users[i].active_treasure_chest.clone() {
-      for item in treasure_chest.contents.iter() {
-        user_list.users[i].inventory.add_item(item);
-      }
-    }
-    user_list.users[i].active_treasure_chest = None;
-    user_list.update_timestamp_of_index(i);
-    user_list.save_to_file(users_file_path);
-    content::Json(serde_json::json!({
-      "username": username,
-      "succeeded": true,
-      "info": "The chest closes and disappears into ethereal green flames\
-              \nwhich radiate no heat."
-    }).to_string())
-  } else {
-    mudnix_utils::error_response(username, "request failed")
-  }
+  player: session::AuthenticatedUser,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let response = game_actions::take(&player.username, users, chat, events)?;
+  Ok(content::Json(response.to_string()))
 }
 
-#[post("/say?<username>&<password>&<message>")]
+#[post("/say?<message>&<channel>")]
 pub fn say(
-  username: &str,
-  password: &str,
+  player: session::AuthenticatedUser,
   message: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> &'static str {
-  /* We use the pool here instead of just loading from the users file
-     because we don't want to let users send messages if they aren't logged in.
+  channel: Option<&str>,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  /* The session guard ensures only logged-in users can send messages.
      Otherwise it would be trivial to write a script to spam people with messages. */
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-  let password_hash = mudnix_utils::hash(password);
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    let mut message_queue = message::MessageQueue::new("/home/runner/mudnix/message_queue");
-    message_queue.send_message(message::Message::new(
-      message, username, &user_list.users[i].world_location
-    ));
-    user_list.update_timestamp_of_index(i);
-    user_list.save_to_file(users_file_path);
-    "Ok"
-  } else {
-    "Denied"
-  }
+  let response = game_actions::say(&player.username, message, channel, users, chat, events)?;
+  Ok(content::Json(response.to_string()))
 }
 
-#[get("/message-queue?<username>&<password>")]
-pub fn get_messages(
-  username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> EventStream![] {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let starting_user_list = user::UserList::from_file(users_file_path);
+#[post("/emote?<action>")]
+pub fn emote(
+  player: session::AuthenticatedUser,
+  action: &str,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let response = game_actions::emote(&player.username, action, users, chat, events)?;
+  Ok(content::Json(response.to_string()))
+}
 
-  // we make this copy so we don't have to put the borrow inside the EventStream
-  let username_copy = String::from(username);
+#[post("/whisper?<to>&<message>")]
+pub fn whisper(
+  player: session::AuthenticatedUser,
+  to: &str,
+  message: &str,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let response = game_actions::whisper(&player.username, to, message, users, world, chat, events)?;
+  Ok(content::Json(response.to_string()))
+}
 
-  let password_hash = mudnix_utils::hash(password);
+#[post("/tell?<to>&<message>")]
+pub fn tell(
+  player: session::AuthenticatedUser,
+  to: &str,
+  message: &str,
+  users: &State<user_store::Users>,
+  logged_in_user_pool: &State<mudnix_utils::LoggedInUserPool>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  let response = game_actions::tell(
+    &player.username, to, message, users, logged_in_user_pool, chat, events
+  )?;
+  Ok(content::Json(response.to_string()))
+}
 
-  let starting_world_location: String = if let Some(i) = starting_user_list.get_index_if_valid_creds(
-    &username_copy, &password_hash
-  ) {
-    starting_user_list.users[i].world_location.clone()
-  } else {
-    String::from("invalid")
-  };
+#[get("/channels")]
+pub fn channels(
+  player: session::AuthenticatedUser,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>
+) -> JsonResult {
+  let response = game_actions::list_channels(&player.username, users, chat)?;
+  Ok(content::Json(response.to_string()))
+}
+
+#[post("/channels/join?<name>")]
+pub fn join_channel(
+  player: session::AuthenticatedUser,
+  name: &str,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>
+) -> JsonResult {
+  let response = game_actions::join_channel(&player.username, name, users, chat)?;
+  Ok(content::Json(response.to_string()))
+}
+
+#[post("/channels/leave?<name>")]
+pub fn leave_channel(
+  player: session::AuthenticatedUser,
+  name: &str,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>
+) -> JsonResult {
+  let response = game_actions::leave_channel(&player.username, name, users, chat)?;
+  Ok(content::Json(response.to_string()))
+}
+
+// the most messages sent back by one request for chat history
+const MAX_CHAT_HISTORY_PAGE: usize = 500;
 
-  // we copy the file path here, but it's fine because we are only reading the file, not writing it
-  let users_file_path_copy: String = String::from(users_file_path);
+fn chat_event(username: &str, message: message::Message) -> Event {
+  Event::data(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "queue": [&message]
+  }).to_string()).id(message.id.to_string())
+}
 
-  let mut message_queue = message::MessageQueue::new("/home/runner/mudnix/message_queue");
-  let mut interval = time::interval(Duration::from_millis(100));
+/* Streams everything the user can hear: the chat at their location
+   (following them as they move), private messages to and from them, and
+   the global, region and joined channels. A client that reconnects with
+   `Last-Event-ID` first gets whatever it missed that is still in the
+   history. */
+#[get("/message-queue")]
+pub fn get_messages<'a>(
+  player: session::AuthenticatedUser,
+  last_event_id: message::LastEventId,
+  world: &'a State<world_map::World>,
+  sessions: &'a State<session::SessionStore>,
+  chat: &'a State<message::ChatBus>
+) -> EventStream<impl Stream<Item = Event> + 'a> {
+  // how often to check whether the session is still active and what the user hears
+  let mut interval = time::interval(Duration::from_secs(1));
+  let replay_since = last_event_id.0;
   EventStream! {
-    if starting_world_location != "invalid" {
-      loop {
-        let fresh_user_list = user::UserList::from_file(&users_file_path_copy);
-        let i = fresh_user_list.get_index_if_valid_creds(&username_copy, &password_hash).unwrap();
-        let current_location = fresh_user_list.users[i].world_location.clone();
-        interval.tick().await;
-        message_queue.flush_queue();
-        let messages = message_queue.get_messages(&current_location);
-        if messages.len() > 0 {
-          yield Event::data(serde_json::json!({
-            "username": username_copy,
-            "succeeded": true,
-            "queue": messages
-          }).to_string());
+    // messages from users on the player's ignore list are left out
+    let ignored = |message: &message::Message| chat.is_ignoring(&player.username, &message.user);
+    // the newest message the client has had, or that was sent before it connected
+    let mut last_id = chat.last_id();
+    let mut scopes = chat.scopes_of(&player.username, world.location_of(&player.username).as_deref());
+    let (missed, mut messages) = chat.subscribe_all(&scopes, replay_since);
+    for message in missed {
+      last_id = last_id.max(message.id);
+      if !ignored(&message) {
+        yield chat_event(&player.username, message);
+      }
+    }
+
+    // the stream ends once the session is logged out or expires
+    while sessions.is_active(&player.token) {
+      let current_scopes = chat.scopes_of(
+        &player.username,
+        world.location_of(&player.username).as_deref()
+      );
+      if current_scopes != scopes {
+        /* A player who has just moved or joined a channel doesn't get a replay
+           of it, only what was sent since the last message they got, which
+           would otherwise be lost while resubscribing. */
+        scopes = current_scopes;
+        let (missed, resubscribed) = chat.subscribe_all(&scopes, Some(last_id));
+        messages = resubscribed;
+        for message in missed {
+          last_id = last_id.max(message.id);
+          if !ignored(&message) {
+            yield chat_event(&player.username, message);
+          }
+        }
+      }
+
+      let message = tokio::select! {
+        message = messages.next() => message,
+        _ = interval.tick() => None
+      };
+      if let Some(message) = message {
+        last_id = last_id.max(message.id);
+        if !ignored(&message) {
+          yield chat_event(&player.username, message);
         }
       }
-    } else {
-      yield Event::data(serde_json::json!({
-        "username": username_copy,
-        "succeeded": false,
-        "err": "You are not logged in."
-      }).to_string());
     }
+    yield Event::data(MudnixError::NotLoggedIn.to_json().to_string());
   }
 }
 
-#[get("/whos-here?<username>&<password>")]
-pub fn whos_here(
-  username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-  let password_hash = mudnix_utils::hash(password);
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    let location_id = user_list.users[i].world_location.clone();
-    let location = match world_map::WorldLocation::from_location_id(&location_id) {
-      Ok(location) => location,
-      Err(_) => return mudnix_utils::error_response(
-        username,
-        &format!(
-          "you are currently located at invalid location \"{}\"",
-          location_id
-        )
-      )
-    };
+// the chat at the user's location, newest first, `count` messages per page
+#[get("/chat-history?<count>&<page>")]
+pub fn chat_history(
+  player: session::AuthenticatedUser,
+  count: Option<usize>,
+  page: Option<usize>,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>
+) -> JsonResult {
+  let username: &str = &player.username;
+  let user = mudnix_utils::touch_user(users, username)?;
+  let count = count.unwrap_or(20).clamp(1, MAX_CHAT_HISTORY_PAGE);
+  let page = page.unwrap_or(0);
+  let messages = chat.log().recent(&user.world_location, count, page)?;
+  Ok(content::Json(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "active_location": user.world_location,
+    "page": page,
+    "messages": messages
+  }).to_string()))
+}
 
-    let subloc = world_map::get_sublocation_from_id(&location_id).unwrap();
-    let nearby_users: Vec<String> = match location.get_users_from_sublocation(&subloc) {
-      Ok(users) => users,
-      Err(_) => return mudnix_utils::error_response(
-        username,
-        &format!(
-          "unable to get users at invalid location \"{}\"",
-          location_id
-        )
-      )
-    };
-    user_list.update_timestamp_of_index(i);
-    user_list.save_to_file(users_file_path);
-    content::Json(serde_json::json!({
-      "username": username,
-      "succeeded": true,
-      "active_location": location_id,
-      "nearby_users": nearby_users
-    }).to_string())
-  } else {
-    mudnix_utils::error_response(username, "invalid credentials")
-  }
+/* Searches the whole chat log, including private and channel messages,
+   for moderators settling disputes. `since` and `until` are Unix timestamps. */
+#[get("/chat-history/search?<user>&<location>&<since>&<until>&<limit>")]
+pub fn search_chat_history(
+  moderator: session::Authorized<session::CanModerate>,
+  user: Option<&str>,
+  location: Option<&str>,
+  since: Option<u64>,
+  until: Option<u64>,
+  limit: Option<usize>,
+  chat: &State<message::ChatBus>
+) -> JsonResult {
+  let messages = chat.log().search(&chat_log::SearchQuery {
+    user,
+    location_id: location,
+    since,
+    until,
+    limit: limit.unwrap_or(MAX_CHAT_HISTORY_PAGE).min(MAX_CHAT_HISTORY_PAGE)
+  })?;
+  Ok(content::Json(serde_json::json!({
+    "username": moderator.user.username,
+    "succeeded": true,
+    "messages": messages
+  }).to_string()))
+}
+
+#[get("/whos-here")]
+pub fn whos_here(
+  player: session::AuthenticatedUser,
+  users: &State<user_store::Users>,
+  world: &State<world_map::World>
+) -> JsonResult {
+  let username: &str = &player.username;
+  let user = mudnix_utils::touch_user(users, username)?;
+  let location_id = user.world_location.clone();
+  let nearby_users: Vec<String> = world.users_at(&location_id)?;
+  Ok(content::Json(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "active_location": location_id,
+    "nearby_users": nearby_users
+  }).to_string()))
 }
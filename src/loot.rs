@@ -0,0 +1,294 @@
+/**
+ * Loot tables, which decide what turns up in treasure chests. Each file in
+ * the loot directory is one table, used either for a location (a
+ * WorldLocation by name, or one sublocation by location ID) or for every
+ * location with a given `Biome.eco`, e.g.
+ *
+ *   { "eco": "plains", "rolls": 2,
+ *     "entries": [{ "t": "copper_coin", "weight": 10, "min_qty": 1, "max_qty": 5 }] }
+ *
+ * The most specific table wins: sublocation, then WorldLocation, then eco.
+ * A table with neither `eco` nor `location` is used everywhere else.
+ */
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+
+use crate::entities::{Inventory, Item, ItemContainer, ItemTemplate};
+use crate::mudnix_utils;
+use crate::world_map;
+
+// the most a table may roll, and the most of an item an entry may give, so chests stay small
+const MAX_ROLLS: u32 = 100;
+const MAX_QTY: u32 = 1000;
+
+fn one() -> u32 {
+  1
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LootEntry {
+  pub t: String,
+  // how often the entry is picked relative to the others; by default, from the item's Rarity
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub weight: Option<u32>,
+  #[serde(default = "one")]
+  pub min_qty: u32,
+  #[serde(default = "one")]
+  pub max_qty: u32
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LootTable {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub eco: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub location: Option<String>,
+  #[serde(default = "one")]
+  pub rolls: u32, // entries picked per chest; the same one may come up more than once
+  pub entries: Vec<LootEntry>
+}
+
+impl LootTable {
+  pub fn from_file(file_path: &str) -> Result<Self, io::Error> {
+    mudnix_utils::read_json_with_backup(file_path)
+  }
+
+  // brings `rolls` and quantities down to MAX_ROLLS and MAX_QTY, saying what was changed
+  fn cap(&mut self) -> Vec<String> {
+    let mut problems: Vec<String> = vec![];
+    if self.rolls > MAX_ROLLS {
+      problems.push(format!("rolls can be at most {}, not {}", MAX_ROLLS, self.rolls));
+      self.rolls = MAX_ROLLS;
+    }
+    for entry in self.entries.iter_mut() {
+      if entry.min_qty > MAX_QTY || entry.max_qty > MAX_QTY {
+        problems.push(format!("the quantity of {} can be at most {}", entry.t, MAX_QTY));
+        entry.min_qty = entry.min_qty.min(MAX_QTY);
+        entry.max_qty = entry.max_qty.min(MAX_QTY);
+      }
+    }
+    problems
+  }
+
+  // lower is more specific; None if the table doesn't apply to the location
+  fn specificity(&self, location_id: &str, eco: &str) -> Option<u8> {
+    match (&self.location, &self.eco) {
+      (Some(location), _) if location == location_id => Some(0),
+      (Some(location), _) if *location == world_map::get_parent_location_from_id(location_id) => Some(1),
+      (Some(_), _) => None,
+      (None, Some(table_eco)) if table_eco == eco => Some(2),
+      (None, Some(_)) => None,
+      (None, None) => Some(3)
+    }
+  }
+
+  fn weights(&self, templates: &HashMap<String, ItemTemplate>) -> Vec<u64> {
+    self.entries.iter()
+      .map(|entry| u64::from(entry.weight.unwrap_or_else(|| {
+        templates.get(&entry.t).map_or(0, |template| template.rarity.weight())
+      })))
+      .collect()
+  }
+
+  // None if the weights add up to more than fits in a u64
+  fn total_weight(weights: &[u64]) -> Option<u64> {
+    weights.iter().try_fold(0u64, |total, weight| total.checked_add(*weight))
+  }
+
+  /* Picks `rolls` entries at random by weight, each with a random quantity.
+     `templates` gives the default weights. */
+  pub fn roll(&self, templates: &HashMap<String, ItemTemplate>, rng: &mut impl Rng) -> Inventory {
+    let weights = self.weights(templates);
+    let mut contents: Inventory = vec![];
+    let total = match LootTable::total_weight(&weights) {
+      Some(total) if total > 0 => total,
+      _ => return contents
+    };
+    for _ in 0..self.rolls {
+      let mut pick = rng.gen_range(0..total);
+      for (entry, weight) in self.entries.iter().zip(weights.iter()) {
+        if pick < *weight {
+          let qty = rng.gen_range(entry.min_qty..=entry.max_qty.max(entry.min_qty));
+          if qty > 0 {
+            contents.add_item(&Item::new(&entry.t, qty));
+          }
+          break;
+        }
+        pick -= weight;
+      }
+    }
+    contents
+  }
+}
+
+/* Reads every loot table in the loot directory, along with what is wrong
+   with any that can't be used. Entries for items that aren't in
+   `templates` are left out, so chests only hold items that exist, rolls
+   and quantities over MAX_ROLLS and MAX_QTY are brought down to them, and
+   tables whose weights add up to too much to roll are left out. */
+pub fn read_tables(
+  loot_dir: &str,
+  templates: &HashMap<String, ItemTemplate>
+) -> (Vec<LootTable>, Vec<String>) {
+  let mut tables: Vec<LootTable> = vec![];
+  let mut problems: Vec<String> = vec![];
+  let entries = match fs::read_dir(loot_dir) {
+    Ok(entries) => entries,
+    Err(e) => return (tables, vec![format!("{}: {}", loot_dir, e)])
+  };
+  let mut paths: Vec<_> = entries.filter_map(|entry| entry.ok())
+    .map(|entry| entry.path())
+    .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+    .collect();
+  paths.sort(); // so that ties between tables are settled the same way every time
+  for path in paths {
+    match LootTable::from_file(&format!("{}", path.display())) {
+      Ok(mut table) => {
+        table.entries.retain(|entry| {
+          let known = templates.contains_key(&entry.t);
+          if !known {
+            problems.push(format!("{}: there is no item {}", path.display(), entry.t));
+          }
+          known
+        });
+        for problem in table.cap() {
+          problems.push(format!("{}: {}", path.display(), problem));
+        }
+        if LootTable::total_weight(&table.weights(templates)).is_none() {
+          problems.push(format!("{}: the weights add up to too much", path.display()));
+        } else {
+          tables.push(table);
+        }
+      },
+      Err(e) => problems.push(format!("{}: {}", path.display(), e))
+    }
+  }
+  (tables, problems)
+}
+
+// the table to fill a chest from at the given location, if any
+pub fn table_for<'a>(
+  tables: &'a [LootTable],
+  location_id: &str,
+  eco: &str
+) -> Option<&'a LootTable> {
+  tables.iter()
+    .filter_map(|table| table.specificity(location_id, eco).map(|specificity| (specificity, table)))
+    .min_by_key(|(specificity, _)| *specificity)
+    .map(|(_, table)| table)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::SeedableRng;
+  use rand::rngs::StdRng;
+  use crate::entities::Rarity;
+
+  fn templates() -> HashMap<String, ItemTemplate> {
+    [("copper_coin", Rarity::Common), ("pond_pearl", Rarity::Legendary)].iter()
+      .map(|(t, rarity)| (String::from(*t), ItemTemplate {
+        t: String::from(*t),
+        name: t.replace('_', " "),
+        description: String::new(),
+        rarity: *rarity
+      }))
+      .collect()
+  }
+
+  fn table(rolls: u32, entries: serde_json::Value) -> LootTable {
+    serde_json::from_value(serde_json::json!({ "rolls": rolls, "entries": entries })).unwrap()
+  }
+
+  fn roll(table: &LootTable, seed: u64) -> serde_json::Value {
+    serde_json::to_value(table.roll(&templates(), &mut StdRng::seed_from_u64(seed))).unwrap()
+  }
+
+  #[test]
+  fn same_seed_gives_same_loot() {
+    let table = table(3, serde_json::json!([
+      { "t": "copper_coin", "min_qty": 1, "max_qty": 10 },
+      { "t": "pond_pearl" }
+    ]));
+    for seed in 0..20 {
+      assert_eq!(roll(&table, seed), roll(&table, seed));
+    }
+  }
+
+  #[test]
+  fn quantities_stay_in_range() {
+    let table = table(1, serde_json::json!([{ "t": "copper_coin", "min_qty": 2, "max_qty": 4 }]));
+    for seed in 0..50 {
+      let contents = table.roll(&templates(), &mut StdRng::seed_from_u64(seed));
+      assert_eq!(contents.len(), 1);
+      assert!((2..=4).contains(&contents[0].qty));
+    }
+  }
+
+  #[test]
+  fn rolls_of_the_same_entry_stack() {
+    let table = table(5, serde_json::json!([{ "t": "copper_coin" }]));
+    let contents = table.roll(&templates(), &mut StdRng::seed_from_u64(42));
+    assert_eq!(contents.len(), 1);
+    assert_eq!(contents[0].qty, 5);
+  }
+
+  #[test]
+  fn entries_without_weight_are_never_picked() {
+    let table = table(10, serde_json::json!([
+      { "t": "copper_coin", "weight": 0 },
+      { "t": "pond_pearl", "weight": 1 },
+      { "t": "unknown_item" } // no template, so no weight from a Rarity
+    ]));
+    for seed in 0..20 {
+      let contents = table.roll(&templates(), &mut StdRng::seed_from_u64(seed));
+      assert!(contents.iter().all(|item| item.t == "pond_pearl"));
+    }
+  }
+
+  #[test]
+  fn table_without_weight_is_empty() {
+    let table = table(3, serde_json::json!([{ "t": "copper_coin", "weight": 0 }]));
+    assert!(table.roll(&templates(), &mut StdRng::seed_from_u64(42)).is_empty());
+  }
+
+  #[test]
+  fn overflowing_weights_are_caught() {
+    assert_eq!(LootTable::total_weight(&[u64::MAX - 1, 1]), Some(u64::MAX));
+    assert_eq!(LootTable::total_weight(&[u64::MAX, 1]), None);
+  }
+
+  #[test]
+  fn rolls_and_quantities_are_capped() {
+    let mut table = table(u32::MAX, serde_json::json!([
+      { "t": "copper_coin", "min_qty": u32::MAX, "max_qty": u32::MAX },
+      { "t": "pond_pearl", "max_qty": 3 }
+    ]));
+    assert_eq!(table.cap().len(), 2);
+    assert_eq!(table.rolls, MAX_ROLLS);
+    assert_eq!((table.entries[0].min_qty, table.entries[0].max_qty), (MAX_QTY, MAX_QTY));
+    assert_eq!((table.entries[1].min_qty, table.entries[1].max_qty), (1, 3));
+    assert!(table.cap().is_empty());
+  }
+
+  #[test]
+  fn most_specific_table_wins() {
+    let tables: Vec<LootTable> = vec![
+      serde_json::json!({ "entries": [] }),
+      serde_json::json!({ "eco": "plains", "entries": [] }),
+      serde_json::json!({ "location": "Quux_Plains", "entries": [] }),
+      serde_json::json!({ "location": "Quux_Plains::northern_region", "entries": [] })
+    ].into_iter().map(|table| serde_json::from_value(table).unwrap()).collect();
+    let chosen = |location_id: &str, eco: &str| {
+      let table = table_for(&tables, location_id, eco).unwrap();
+      tables.iter().position(|other| std::ptr::eq(other, table)).unwrap()
+    };
+    assert_eq!(chosen("Quux_Plains::northern_region", "plains"), 3);
+    assert_eq!(chosen("Quux_Plains::central_region", "plains"), 2);
+    assert_eq!(chosen("Foo_Pond::shore", "plains"), 1);
+    assert_eq!(chosen("Foo_Pond::shore", "pond"), 0);
+  }
+}
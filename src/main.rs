@@ -1,7 +1,7 @@
 #[macro_use] extern crate rocket;
 extern crate hex;
 extern crate rand;
-use std::sync::Mutex;
+use std::sync::Arc;
 use rocket::fs::FileServer;
 use rocket::http::Header;
 use rocket::{Request, Response};
@@ -17,6 +17,20 @@ mod mudnix_utils;
 mod message;
 mod game_endpoints;
 mod user_endpoints;
+mod session;
+mod user_store;
+mod config;
+mod error;
+mod events;
+mod game_actions;
+mod websocket;
+mod chat_log;
+mod world_validation;
+mod travel;
+mod worldgen;
+mod loot;
+mod world_builder;
+mod build_endpoints;
 
 // https://stackoverflow.com/a/69342225/10942736
 pub struct CORS;
@@ -73,33 +87,122 @@ fn check_connection() -> EventStream![] {
   }
 }
 
+/* Opens the user database, importing the old users.json file unless the
+   database records that it already has. */
+fn open_user_store(db_path: &str, json_path: &str) -> user_store::Users {
+  let store = user_store::SqliteUserStore::open(db_path)
+    .expect("unable to open user database");
+  let imported = store.import_legacy_json(json_path)
+    .expect("unable to import users from json");
+  if let Some(imported) = imported {
+    println!("Imported {} users from {} into {}", imported, json_path, db_path);
+  }
+  Arc::new(store)
+}
+
+// loads the world map and reports anything wrong with it
+fn load_world(config: &config::MudnixConfig) -> world_map::World {
+  let world = world_map::World::load(&config.map_dir)
+    .expect("unable to load the world map");
+  let problems = world_validation::validate(&world.locations(), &config.spawn_location);
+  for problem in problems.iter() {
+    println!("World map: {}", problem);
+  }
+  if !problems.is_empty() && config.world_validation == config::WorldValidation::Strict {
+    panic!("the world map in {} has {} problem(s)", config.map_dir, problems.len());
+  }
+  world
+}
+
+// loads the item definitions and loot tables, leaving out any that can't be used
+fn load_items(config: &config::MudnixConfig) -> entities::ItemRegistry {
+  let (templates, problems) = entities::ItemRegistry::read(&config.item_dir);
+  for problem in problems.iter() {
+    println!("Items: {}", problem);
+  }
+  let (loot_tables, problems) = loot::read_tables(&config.loot_dir, &templates);
+  for problem in problems.iter() {
+    println!("Loot tables: {}", problem);
+  }
+  entities::ItemRegistry::new(templates, loot_tables, config.loot_seed)
+}
+
 #[launch]
 fn rocket() -> _ {
-  rocket::build()
-    .manage(mudnix_utils::UsersFileMutex {
-      mutex: Mutex::new(String::from("/home/runner/mudnix/users.json"))
-    })
-    .manage(mudnix_utils::LoggedInUserPool {
-      user_list_mutex: Mutex::new(user::UserList::new())
-    })
+  let rocket = rocket::build();
+  let config = config::MudnixConfig::from_figment(rocket.figment())
+    .expect("invalid [mudnix] configuration");
+  message::set_word_filter(&config.filtered_words);
+
+  rocket
+    .manage(open_user_store(&config.users_file, &config.legacy_users_file))
+    .manage(mudnix_utils::LoggedInUserPool::new())
+    .manage(session::SessionStore::new())
+    .manage(events::EventBus::new())
+    .manage(message::ChatBus::new(&config))
+    .manage(travel::Journeys::new(config.travel_step_seconds))
+    .manage(load_world(&config))
+    .manage(load_items(&config))
+    .manage(world_builder::WorldBuilder::new(&config))
+    .mount("/", FileServer::from(&config.static_dir))
+    .manage(config)
     .attach(CORS)
-    .mount("/", FileServer::from("/home/runner/mudnix/static"))
+    .attach(websocket::GameChannel::fairing())
     .mount("/", routes![version, check_connection])
-    .mount("/hash", routes![mudnix_utils::hash])
     .mount("/user", routes![
       user_endpoints::new_user,
       user_endpoints::login,
       user_endpoints::logout,
       user_endpoints::inventory,
-      user_endpoints::autologout
+      user_endpoints::autologout,
+      user_endpoints::set_role,
+      user_endpoints::ignore,
+      user_endpoints::unignore,
+      user_endpoints::mute,
+      user_endpoints::unmute
     ])
     .mount("/game", routes![
       game_endpoints::teleport,
       game_endpoints::goto,
+      game_endpoints::move_direction,
+      game_endpoints::door,
+      game_endpoints::travel_to,
+      game_endpoints::stop_travel,
+      game_endpoints::enter_instance,
+      game_endpoints::accept_invitation,
+      game_endpoints::decline_invitation,
+      game_endpoints::generate_location,
+      game_endpoints::reload,
       game_endpoints::map,
+      game_endpoints::look,
       game_endpoints::close_chest,
       game_endpoints::say,
+      game_endpoints::emote,
+      game_endpoints::whisper,
+      game_endpoints::tell,
+      game_endpoints::channels,
+      game_endpoints::join_channel,
+      game_endpoints::leave_channel,
       game_endpoints::get_messages,
+      game_endpoints::chat_history,
+      game_endpoints::search_chat_history,
       game_endpoints::whos_here
     ])
+    .mount("/build", routes![
+      build_endpoints::create_location,
+      build_endpoints::add_sublocation,
+      build_endpoints::rename_sublocation,
+      build_endpoints::remove_sublocation,
+      build_endpoints::link,
+      build_endpoints::unlink,
+      build_endpoints::set_attrs
+    ])
+    .register("/", catchers![
+      error::bad_request,
+      error::unauthorized,
+      error::forbidden,
+      error::not_found,
+      error::unprocessable_entity,
+      error::internal_error
+    ])
 }
@@ -1,118 +1,429 @@
-use std::fs;
-use std::path::Path;
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime};
 use serde::{Serialize, Deserialize};
-use rand::{distributions::Alphanumeric, Rng};
+use rocket::request::{self, FromRequest, Request};
+use rocket::outcome::Outcome;
+use rocket::futures::stream::{self, BoxStream, SelectAll, StreamExt};
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::chat_log;
+use crate::config;
+use crate::world_map;
+use crate::error::MudnixError;
+
+// words censored in every message, from `filtered_words` in the config
+static WORD_FILTER: OnceLock<Vec<String>> = OnceLock::new();
+
+pub fn set_word_filter(words: &[String]) {
+  let _ = WORD_FILTER.set(words.iter().map(|word| word.to_lowercase()).collect());
+}
+
+// replaces each whole word on the filter, in any case, with asterisks
+fn filter_words(text: &str) -> String {
+  let words = match WORD_FILTER.get() {
+    Some(words) if !words.is_empty() => words,
+    _ => return String::from(text)
+  };
+  let mut filtered = String::with_capacity(text.len());
+  let mut word = String::new();
+  for c in text.chars().chain(std::iter::once(' ')) {
+    if c.is_alphanumeric() || c == '\'' {
+      word.push(c);
+      continue;
+    }
+    if words.contains(&word.to_lowercase()) {
+      filtered.extend(word.chars().map(|_| '*'));
+    } else {
+      filtered.push_str(&word);
+    }
+    word.clear();
+    filtered.push(c);
+  }
+  filtered.pop(); // the space added to flush the last word
+  filtered
+}
+
+// what a message is, so clients can show speech and actions differently
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+  #[default]
+  Say,
+  Emote, // `text` is an action, e.g. "waves", to be shown as "alice waves"
+  System,
+  Arrival,
+  Departure,
+  ItemDrop
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Message {
+  pub id: u64, // assigned by the ChatBus when the message is published
+  #[serde(default)]
+  pub kind: MessageKind,
   pub text: String,
   pub timestamp: u64,
   pub user: String,
-  pub location_id: String
+  pub location_id: String, // where the sender was
+  // set on whispers and tells, which only the sender and recipient see
+  #[serde(default)]
+  pub recipient: Option<String>,
+  // set on messages said on a channel rather than to the location
+  #[serde(default)]
+  pub channel: Option<String>
 }
 
 impl Message {
   pub fn new(text: &str, user: &str, location_id: &str) -> Self {
     Self {
-      text: String::from(text),
+      id: 0,
+      kind: MessageKind::Say,
+      text: filter_words(text),
       timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
         .unwrap().as_secs(),
       user: String::from(user),
-      location_id: String::from(location_id)
+      location_id: String::from(location_id),
+      recipient: None,
+      channel: None
     }
   }
 
-  pub fn from_file(file_path: &str) -> Self {
-    let original_json = fs::read_to_string(file_path).unwrap();
-    serde_json::from_str(&original_json).expect("unable to parse json")
+  // an emote or an event in the game, rather than something said
+  pub fn of_kind(kind: MessageKind, text: &str, user: &str, location_id: &str) -> Self {
+    Self {
+      kind,
+      ..Message::new(text, user, location_id)
+    }
   }
 
-  // messages expire after ten seconds
-  pub fn is_not_expired(&self) -> bool {
-    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
-      .unwrap().as_secs();
-    now - self.timestamp < 10
+  pub fn direct(text: &str, user: &str, location_id: &str, recipient: &str) -> Self {
+    Self {
+      recipient: Some(String::from(recipient)),
+      ..Message::new(text, user, location_id)
+    }
   }
 
-  pub fn is_expired(&self) -> bool {
-    !self.is_not_expired()
+  pub fn on_channel(text: &str, user: &str, location_id: &str, channel: &str) -> Self {
+    Self {
+      channel: Some(String::from(channel)),
+      ..Message::new(text, user, location_id)
+    }
   }
 }
 
-pub struct MessageQueue {
-  // path of directory where files are kept
-  message_queue_path: String,
+// a stream of chat: its recent history and who is listening
+struct ChatChannel {
+  history: VecDeque<Message>,
+  sender: broadcast::Sender<Message>
+}
 
-  // file paths of messages
-  message_paths: Vec<String>
+impl ChatChannel {
+  fn new(history_size: usize) -> Self {
+    Self {
+      history: VecDeque::with_capacity(history_size),
+      sender: broadcast::channel(history_size.max(1)).0
+    }
+  }
+
+  fn send(&mut self, message: &Message, history_size: usize) {
+    if self.history.len() >= history_size {
+      self.history.pop_front();
+    }
+    self.history.push_back(message.clone());
+    // sending only fails when nobody is listening, which is fine
+    let _ = self.sender.send(message.clone());
+  }
+
+  fn subscribe(&self, last_id: Option<u64>) -> (Vec<Message>, broadcast::Receiver<Message>) {
+    let missed: Vec<Message> = match last_id {
+      Some(last_id) => self.history.iter()
+        .filter(|message| message.id > last_id)
+        .cloned()
+        .collect(),
+      None => vec![]
+    };
+    (missed, self.sender.subscribe())
+  }
 }
 
-impl MessageQueue {
-  pub fn new(message_queue_path: &str) -> Self {
-    let mut message_paths: Vec<String> = vec![];
-    let path = Path::new(message_queue_path);
-    for entry in fs::read_dir(path).expect("Unable to read message files") {
-      let entry = entry.expect("unable to get entry");
-      message_paths.push(format!("{}", entry.path().display()));
+// where a message goes, and so who can hear it
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum ChatScope {
+  Location(String),
+  User(String), // private messages to and from a user
+  Channel(String)
+}
+
+impl ChatScope {
+  // the scopes a message is sent to
+  pub fn of(message: &Message) -> Vec<ChatScope> {
+    match (&message.recipient, &message.channel) {
+      (Some(recipient), _) if recipient != &message.user => vec![
+        ChatScope::User(recipient.clone()),
+        ChatScope::User(message.user.clone())
+      ],
+      (Some(recipient), _) => vec![ChatScope::User(recipient.clone())],
+      (None, Some(channel)) => vec![ChatScope::Channel(channel.clone())],
+      (None, None) => vec![ChatScope::Location(message.location_id.clone())]
     }
+  }
+}
+
+// messages from several chat scopes, as returned by `ChatBus::subscribe_all`
+pub type ChatStream = SelectAll<BoxStream<'static, Message>>;
+
+pub const GLOBAL_CHANNEL: &str = "global";
+
+// every WorldLocation has a channel spanning all of its sublocations
+pub fn region_channel_of(location_id: &str) -> String {
+  format!("region:{}", world_map::get_parent_location_from_id(location_id))
+}
+
+// names of player-created channels can't clash with the built-in ones
+pub fn is_valid_channel_name(name: &str) -> bool {
+  !name.is_empty()
+    && name.len() <= 32
+    && name != GLOBAL_CHANNEL
+    && name != "region"
+    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// a user's allowance of messages, refilled over time
+struct TokenBucket {
+  tokens: f64,
+  last_refill: Instant
+}
+
+struct ChatChannels {
+  by_scope: HashMap<ChatScope, ChatChannel>,
+  // members of player-created channels
+  members: HashMap<String, HashSet<String>>,
+  next_id: u64
+}
+
+/**
+ * In-memory chat, with one broadcast channel per location, per user (for
+ * private messages) and per named channel. Each channel keeps its last few
+ * messages so that clients which reconnect can catch up on what they missed.
+ * Message IDs increase across all channels, so a client only ever needs to
+ * remember the last ID it saw.
+ * Clones share the same channels.
+ */
+#[derive(Clone)]
+pub struct ChatBus {
+  channels: Arc<Mutex<ChatChannels>>,
+  rate_limits: Arc<Mutex<HashMap<String, TokenBucket>>>,
+  /* whose messages each logged-in user doesn't want to see, kept here so
+     that filtering a message doesn't mean reading the user from the database */
+  ignore_lists: Arc<Mutex<HashMap<String, Vec<String>>>>,
+  log: chat_log::ChatLog,
+  history_size: usize,
+  max_message_length: usize,
+  burst: u32,
+  messages_per_minute: u32
+}
+
+impl ChatBus {
+  pub fn new(config: &config::MudnixConfig) -> Self {
     Self {
-      message_queue_path: String::from(message_queue_path),
-      message_paths
+      channels: Arc::new(Mutex::new(ChatChannels {
+        by_scope: HashMap::new(),
+        members: HashMap::new(),
+        next_id: 1
+      })),
+      rate_limits: Arc::new(Mutex::new(HashMap::new())),
+      ignore_lists: Arc::new(Mutex::new(HashMap::new())),
+      log: chat_log::ChatLog::new(&config.chat_log_dir),
+      history_size: config.chat_history_size,
+      max_message_length: config.max_message_length,
+      burst: config.chat_burst,
+      messages_per_minute: config.chat_messages_per_minute
     }
   }
 
-  pub fn send_message(&mut self, message: Message) {
-    let random_string: String = rand::thread_rng()
-      .sample_iter(&Alphanumeric)
-      .take(8)
-      .map(char::from)
-      .collect();
+  /* Whether the user may send this text now. Each message uses up a token
+     from the user's bucket, which holds up to `burst` tokens and refills at
+     `messages_per_minute`. */
+  pub fn check_limits(&self, username: &str, text: &str) -> Result<(), MudnixError> {
+    if text.chars().count() > self.max_message_length {
+      return Err(MudnixError::MessageTooLong(self.max_message_length));
+    }
+    let burst = f64::from(self.burst);
+    let mut rate_limits = self.rate_limits.lock().unwrap();
+    let bucket = rate_limits.entry(String::from(username)).or_insert(TokenBucket {
+      tokens: burst,
+      last_refill: Instant::now()
+    });
+    let now = Instant::now();
+    let refill = now.duration_since(bucket.last_refill).as_secs_f64()
+      * f64::from(self.messages_per_minute) / 60.0;
+    bucket.tokens = (bucket.tokens + refill).min(burst);
+    bucket.last_refill = now;
+    if bucket.tokens < 1.0 {
+      return Err(MudnixError::RateLimited);
+    }
+    bucket.tokens -= 1.0;
+    Ok(())
+  }
 
-    // example: 1000212360@Quux_Plains::northern_region@ASk87Fy3.json
-    let out_file_path = format!(
-      "{}/{}@{}@{}.json",
-      self.message_queue_path,
-      message.timestamp,
-      message.location_id,
-      random_string
-    );
-
-    let output_json = serde_json::to_string_pretty(&message).unwrap();
-    fs::write(&out_file_path, output_json).expect("unable to save message");
-
-    self.message_paths.push(out_file_path);
-  }
-
-  // Remove expired messages from the queue and add new messages
-  pub fn flush_queue(&mut self) {
-    let path = Path::new(&self.message_queue_path);
-    for entry in fs::read_dir(path).expect("Unable to read message files") {
-      let entry = entry.expect("unable to get entry");
-      let entry_path = format!("{}", entry.path().display());
-      if !self.message_paths.iter().any(|p| p == &entry_path) {
-        self.message_paths.push(entry_path);
+  // called on login and whenever the user ignores or unignores someone
+  pub fn set_ignored(&self, username: &str, ignored_users: &[String]) {
+    self.ignore_lists.lock().unwrap().insert(String::from(username), ignored_users.to_vec());
+  }
+
+  pub fn is_ignoring(&self, username: &str, sender: &str) -> bool {
+    self.ignore_lists.lock().unwrap().get(username)
+      .is_some_and(|ignored_users| ignored_users.iter().any(|ignored| ignored == sender))
+  }
+
+  // drops what is kept about the user while they're logged in, once they log out
+  pub fn forget(&self, username: &str) {
+    self.rate_limits.lock().unwrap().remove(username);
+    self.ignore_lists.lock().unwrap().remove(username);
+  }
+
+  // every message that is published is also written here
+  pub fn log(&self) -> &chat_log::ChatLog {
+    &self.log
+  }
+
+  // gives the message an ID, sends it to everyone who can hear it and logs it
+  pub fn publish(&self, mut message: Message) -> Message {
+    {
+      let mut channels = self.channels.lock().unwrap();
+      message.id = channels.next_id;
+      channels.next_id += 1;
+      for scope in ChatScope::of(&message) {
+        channels.by_scope.entry(scope)
+          .or_insert_with(|| ChatChannel::new(self.history_size))
+          .send(&message, self.history_size);
       }
     }
 
-    for path in self.message_paths.iter_mut() {
-      if Path::new(&path).exists() {
-        if Message::from_file(&path).is_expired() {
-          fs::remove_file(&path).expect("unable to remove file");
-        }
-      }
+    /* Written once the channels are unlocked, so a slow disk doesn't hold up
+       everyone else's chat. Losing a line of the log isn't worth failing to
+       deliver the message over. */
+    if let Err(e) = self.log.append(&message) {
+      println!("Unable to write message {} to the chat log: {}", message.id, e);
     }
-    self.message_paths.retain(|path| Path::new(&path).exists());
+    message
   }
 
-  pub fn get_messages(&self, location_id: &str) -> Vec<Message> {
-    let mut result: Vec<Message> = vec![];
-    for path in self.message_paths.iter() {
-      let message = Message::from_file(&path);
-      if message.location_id == location_id /*&& message.is_not_expired()*/ {
-        result.push(message);
+  // the ID of the newest message published so far
+  pub fn last_id(&self) -> u64 {
+    self.channels.lock().unwrap().next_id - 1
+  }
+
+  /* Subscribes to a scope. Also returns the messages in its history newer
+     than `last_id`, so nothing is lost in between. */
+  pub fn subscribe(
+    &self,
+    scope: &ChatScope,
+    last_id: Option<u64>
+  ) -> (Vec<Message>, broadcast::Receiver<Message>) {
+    let mut channels = self.channels.lock().unwrap();
+    channels.by_scope.entry(scope.clone())
+      .or_insert_with(|| ChatChannel::new(self.history_size))
+      .subscribe(last_id)
+  }
+
+  // adds the user to a player-created channel; returns true if it was just created
+  pub fn join(&self, username: &str, channel: &str) -> bool {
+    let mut channels = self.channels.lock().unwrap();
+    let is_new = !channels.members.contains_key(channel);
+    channels.members.entry(String::from(channel)).or_default()
+      .insert(String::from(username));
+    is_new
+  }
+
+  pub fn leave(&self, username: &str, channel: &str) {
+    let mut channels = self.channels.lock().unwrap();
+    if let Some(members) = channels.members.get_mut(channel) {
+      members.remove(username);
+      if members.is_empty() {
+        channels.members.remove(channel);
+        channels.by_scope.remove(&ChatScope::Channel(String::from(channel)));
       }
     }
-    result
+  }
+
+  pub fn is_member(&self, username: &str, channel: &str) -> bool {
+    match self.channels.lock().unwrap().members.get(channel) {
+      Some(members) => members.contains(username),
+      None => false
+    }
+  }
+
+  // player-created channels and how many members each has
+  pub fn list(&self) -> Vec<(String, usize)> {
+    let mut list: Vec<(String, usize)> = self.channels.lock().unwrap().members.iter()
+      .map(|(channel, members)| (channel.clone(), members.len()))
+      .collect();
+    list.sort();
+    list
+  }
+
+  /* Everything a user standing at `location_id` hears: the location itself,
+     their private messages, the global and region channels and every
+     channel they have joined. */
+  pub fn scopes_of(&self, username: &str, location_id: Option<&str>) -> Vec<ChatScope> {
+    let mut scopes = vec![
+      ChatScope::User(String::from(username)),
+      ChatScope::Channel(String::from(GLOBAL_CHANNEL))
+    ];
+    if let Some(location_id) = location_id {
+      scopes.push(ChatScope::Location(String::from(location_id)));
+      scopes.push(ChatScope::Channel(region_channel_of(location_id)));
+    }
+    let mut joined: Vec<String> = self.channels.lock().unwrap().members.iter()
+      .filter(|(_, members)| members.contains(username))
+      .map(|(channel, _)| channel.clone())
+      .collect();
+    joined.sort();
+    scopes.extend(joined.into_iter().map(ChatScope::Channel));
+    scopes
+  }
+
+  /* Subscribes to several scopes at once, merged into a single stream.
+     Like `subscribe`, also returns what was missed since `last_id`. */
+  pub fn subscribe_all(
+    &self,
+    scopes: &[ChatScope],
+    last_id: Option<u64>
+  ) -> (Vec<Message>, ChatStream) {
+    let mut missed: Vec<Message> = vec![];
+    let mut streams: Vec<BoxStream<'static, Message>> = vec![];
+    for scope in scopes {
+      let (missed_here, receiver) = self.subscribe(scope, last_id);
+      missed.extend(missed_here);
+      streams.push(stream::unfold(receiver, |mut receiver| async move {
+        loop {
+          match receiver.recv().await {
+            Ok(message) => return Some((message, receiver)),
+            // a lagging client skips the messages it fell behind on
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None
+          }
+        }
+      }).boxed());
+    }
+    missed.sort_by_key(|message| message.id);
+    missed.dedup_by_key(|message| message.id);
+    (missed, stream::select_all(streams))
+  }
+}
+
+// the `Last-Event-ID` header sent by SSE clients when they reconnect
+pub struct LastEventId(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+    let last_id = request.headers().get_one("Last-Event-ID")
+      .and_then(|id| id.trim().parse::<u64>().ok());
+    Outcome::Success(LastEventId(last_id))
   }
 }
@@ -1,73 +1,188 @@
-use std::sync::Mutex;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use sha2::{Sha256, Digest};
-use rocket::response::content;
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{PasswordHash, SaltString};
+use serde::de::DeserializeOwned;
 use serde_json;
 
 use crate::user;
-use crate::world_map;
+use crate::user_store;
+use crate::error::MudnixError;
 
-pub struct FilePathMutex {
-  pub mutex: Mutex<String>
+// clones share the same pool
+#[derive(Clone)]
+pub struct LoggedInUserPool {
+  pub user_list_mutex: Arc<Mutex<user::UserList>>
 }
 
-pub type UsersFileMutex = FilePathMutex;
+impl LoggedInUserPool {
+  pub fn new() -> Self {
+    Self {
+      user_list_mutex: Arc::new(Mutex::new(user::UserList::new()))
+    }
+  }
 
-pub struct LoggedInUserPool {
-  pub user_list_mutex: Mutex<user::UserList>
+  pub fn contains(&self, username: &str) -> bool {
+    self.user_list_mutex.lock().unwrap().contains(username)
+  }
 }
 
-#[get("/sha256?<s>")]
-pub fn hash(s: &str) -> String {
+/* Bare SHA-256 hex digest. Only used to check passwords saved before we
+   switched to argon2; new hashes are made with `hash_password`. */
+pub fn sha256_hex(s: &str) -> String {
   let mut hasher = Sha256::new();
   hasher.update(s);
   let hash = hasher.finalize();
   hex::encode(hash)
 }
 
-pub fn error_response(username: &str, error_response: &str) -> content::Json<String> {
-  content::Json(serde_json::json!({
-    "username": username,
-    "succeeded": false,
-    "err": error_response
-  }).to_string())
+// salted argon2id hash in PHC string format
+pub fn hash_password(password: &str) -> String {
+  let salt = SaltString::generate(&mut rand::thread_rng());
+  Argon2::default()
+    .hash_password(password.as_bytes(), &salt)
+    .expect("unable to hash password")
+    .to_string()
 }
 
-pub fn move_user(
-  username: &str,
-  password_hash: &str,
-  new_location_id: &str,
-  users_file_path: &str,
-  user_list: &mut user::UserList
-) -> content::Json<String> {
-  if let Some(i) = user_list.get_index_if_valid_creds(username, password_hash) {
-    let old_location_id: &str = &user_list.users[i].world_location;
-    let mut old_location = match world_map::WorldLocation::from_location_id(old_location_id) {
-      Ok(current_location) => current_location,
-      Err(_) => return error_response(
-        username,
-        &format!("cannot move you from invalid location {}", old_location_id)
-      )
-    };
-    let response = match old_location.move_user_from(
-      username, old_location_id
-    ).to(new_location_id) {
-      Ok(r) => r,
-      Err(_) => return error_response(
-        username,
-        &format!("cannot move you to invalid location {}", new_location_id)
-      )
-    };
-
-    user_list.users[i].world_location = String::from(new_location_id);
-    user_list.update_timestamp_of_index(i);
-    user_list.save_to_file(users_file_path);
-    content::Json(serde_json::json!({
-      "username": username,
-      "succeeded": true,
-      "info": response,
-      "active_treasure_chest": user_list.users[i].active_treasure_chest
-    }).to_string())
+// PHC strings always start with '$'; legacy hashes are 64 hex characters
+pub fn is_legacy_password_hash(password_hash: &str) -> bool {
+  !password_hash.starts_with('$')
+}
+
+// checks a password against either an argon2 PHC string or a legacy SHA-256 hash
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+  if is_legacy_password_hash(password_hash) {
+    sha256_hex(password) == password_hash
   } else {
-    error_response(username, "invalid credentials")
+    match PasswordHash::new(password_hash) {
+      Ok(parsed_hash) => Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok(),
+      Err(_) => false
+    }
+  }
+}
+
+fn backup_path_of(file_path: &str) -> String {
+  format!("{}.bak", file_path)
+}
+
+fn sync_parent_dir(file_path: &str) -> Result<(), io::Error> {
+  match Path::new(file_path).parent() {
+    Some(dir) if dir != Path::new("") => fs::File::open(dir)?.sync_all(),
+    _ => Ok(())
+  }
+}
+
+// numbers temporary files, so that concurrent writes to one file don't share one
+static NEXT_TMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/* Writes to a temporary file in the same directory, fsyncs it, then renames
+   it over the destination, so the destination is never left half-written. */
+fn write_atomically(file_path: &str, contents: &str) -> Result<(), io::Error> {
+  let tmp_path = format!(
+    "{}.{}.{}.tmp",
+    file_path,
+    process::id(),
+    NEXT_TMP_ID.fetch_add(1, Ordering::Relaxed)
+  );
+  let mut tmp_file = fs::File::create(&tmp_path)?;
+  tmp_file.write_all(contents.as_bytes())?;
+  tmp_file.sync_all()?;
+  fs::rename(&tmp_path, file_path)?;
+  sync_parent_dir(file_path)
+}
+
+/* How every JSON state file is saved: the current version of the file is
+   first copied to `<file_path>.bak`, so that read_json_with_backup has
+   something to fall back to, then the new contents are written over it.
+   Both writes are atomic, so the file itself is never missing. A current
+   version that isn't valid JSON (e.g. after a bad hand edit) is not copied,
+   so it can't replace the last good backup. */
+pub fn write_atomically_with_backup(file_path: &str, contents: &str) -> Result<(), io::Error> {
+  if Path::new(file_path).exists() {
+    let current = fs::read_to_string(file_path)?;
+    if serde_json::from_str::<serde_json::Value>(&current).is_ok() {
+      write_atomically(&backup_path_of(file_path), &current)?;
+    }
+  }
+  write_atomically(file_path, contents)
+}
+
+fn read_json<T: DeserializeOwned>(file_path: &str) -> Result<T, io::Error> {
+  let original_json = fs::read_to_string(file_path)?;
+  serde_json::from_str(&original_json)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// reads a JSON file, falling back to its backup if it is missing or corrupt
+pub fn read_json_with_backup<T: DeserializeOwned>(file_path: &str) -> Result<T, io::Error> {
+  match read_json(file_path) {
+    Ok(result) => Ok(result),
+    Err(e) => {
+      let backup_path = backup_path_of(file_path);
+      if Path::new(&backup_path).exists() {
+        println!("Unable to load {} ({}), using {} instead", file_path, e, backup_path);
+        read_json(&backup_path)
+      } else {
+        Err(e)
+      }
+    }
+  }
+}
+
+pub fn get_user(users: &user_store::Users, username: &str) -> Result<user::User, MudnixError> {
+  match users.get(username)? {
+    Some(user) => Ok(user),
+    None => Err(MudnixError::UserNotFound(String::from(username)))
+  }
+}
+
+// applies `f` to the user in a single transaction and returns the updated user
+pub fn update_user(
+  users: &user_store::Users,
+  username: &str,
+  f: &mut dyn FnMut(&mut user::User)
+) -> Result<user::User, MudnixError> {
+  match users.update(username, f)? {
+    Some(user) => Ok(user),
+    None => Err(MudnixError::UserNotFound(String::from(username)))
+  }
+}
+
+// records activity by the user and returns the updated user
+pub fn touch_user(users: &user_store::Users, username: &str) -> Result<user::User, MudnixError> {
+  match users.touch(username)? {
+    Some(user) => Ok(user),
+    None => Err(MudnixError::UserNotFound(String::from(username)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn corrupt_file_does_not_replace_backup() {
+    let dir = std::env::temp_dir().join(format!("mudnix_utils_test_{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file_path = format!("{}", dir.join("location.json").display());
+
+    write_atomically_with_backup(&file_path, "{\"version\": 1}").unwrap();
+    write_atomically_with_backup(&file_path, "{\"version\": 2}").unwrap();
+    assert_eq!(fs::read_to_string(backup_path_of(&file_path)).unwrap(), "{\"version\": 1}");
+
+    fs::write(&file_path, "{\"version\": 2").unwrap(); // a bad hand edit
+    write_atomically_with_backup(&file_path, "{\"version\": 3}").unwrap();
+    assert_eq!(fs::read_to_string(backup_path_of(&file_path)).unwrap(), "{\"version\": 1}");
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), "{\"version\": 3}");
+
+    fs::remove_dir_all(&dir).unwrap();
   }
 }
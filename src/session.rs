@@ -0,0 +1,217 @@
+/**
+ * Session tokens. `login` issues an opaque token which every other
+ * endpoint accepts through the `AuthenticatedUser` request guard, either
+ * as an `Authorization: Bearer <token>` header or as a cookie.
+ * Privileged endpoints use the `Authorized<P>` guard instead, which also
+ * checks that the user's role grants the permission `P`.
+ */
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use rand::{distributions::Alphanumeric, Rng};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::outcome::Outcome;
+
+use crate::user;
+use crate::user_store;
+
+pub const SESSION_COOKIE: &str = "mudnix_session";
+
+// sessions expire after 30 minutes without any activity
+const SESSION_LIFETIME_SECS: u64 = 1800;
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap().as_secs()
+}
+
+pub struct Session {
+  pub username: String,
+  pub expires_at: u64 // seconds since Unix epoch
+}
+
+impl Session {
+  pub fn is_expired(&self) -> bool {
+    now() >= self.expires_at
+  }
+}
+
+// clones share the same sessions
+#[derive(Clone)]
+pub struct SessionStore {
+  sessions: Arc<Mutex<HashMap<String, Session>>>
+}
+
+impl SessionStore {
+  pub fn new() -> Self {
+    Self {
+      sessions: Arc::new(Mutex::new(HashMap::new()))
+    }
+  }
+
+  /* Creates a new session for the user and returns its token. Expired
+     sessions are dropped here too, since a token that's never used again
+     is never touched. */
+  pub fn create(&self, username: &str) -> String {
+    let token: String = rand::thread_rng()
+      .sample_iter(&Alphanumeric)
+      .take(48)
+      .map(char::from)
+      .collect();
+
+    let mut sessions = self.sessions.lock().unwrap();
+    sessions.retain(|_, session| !session.is_expired());
+    sessions.insert(token.clone(), Session {
+      username: String::from(username),
+      expires_at: now() + SESSION_LIFETIME_SECS
+    });
+    token
+  }
+
+  /* Returns the username the token belongs to and pushes back its expiry,
+     or None if the token is unknown or has expired. */
+  pub fn touch(&self, token: &str) -> Option<String> {
+    let mut sessions = self.sessions.lock().unwrap();
+    let expired = match sessions.get_mut(token) {
+      Some(session) if !session.is_expired() => {
+        session.expires_at = now() + SESSION_LIFETIME_SECS;
+        return Some(session.username.clone());
+      },
+      Some(_) => true,
+      None => false
+    };
+    if expired {
+      sessions.remove(token);
+    }
+    None
+  }
+
+  pub fn is_active(&self, token: &str) -> bool {
+    match self.sessions.lock().unwrap().get(token) {
+      Some(session) => !session.is_expired(),
+      None => false
+    }
+  }
+
+  // whether the user has any session that hasn't expired, e.g. after logging in again
+  pub fn has_active_session(&self, username: &str) -> bool {
+    self.sessions.lock().unwrap().values()
+      .any(|session| session.username == username && !session.is_expired())
+  }
+
+  pub fn revoke(&self, token: &str) {
+    self.sessions.lock().unwrap().remove(token);
+  }
+}
+
+// request guard for endpoints that require a logged-in user
+pub struct AuthenticatedUser {
+  pub username: String,
+  pub token: String
+}
+
+fn token_from_request(request: &Request<'_>) -> Option<String> {
+  if let Some(header) = request.headers().get_one("Authorization") {
+    if let Some(token) = header.strip_prefix("Bearer ") {
+      return Some(String::from(token.trim()));
+    }
+  }
+  request.cookies().get(SESSION_COOKIE)
+    .map(|cookie| String::from(cookie.value()))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+  type Error = &'static str;
+
+  async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+    let sessions = match request.rocket().state::<SessionStore>() {
+      Some(sessions) => sessions,
+      None => return Outcome::Failure((
+        Status::InternalServerError, "session store is not configured"
+      ))
+    };
+    let token = match token_from_request(request) {
+      Some(token) => token,
+      None => return Outcome::Failure((Status::Unauthorized, "no session token"))
+    };
+    match sessions.touch(&token) {
+      Some(username) => Outcome::Success(AuthenticatedUser { username, token }),
+      None => Outcome::Failure((Status::Unauthorized, "invalid or expired session"))
+    }
+  }
+}
+
+// marker types naming the permission required by an `Authorized` guard
+pub trait RequiredPermission {
+  const PERMISSION: user::Permission;
+}
+
+pub struct CanTeleport;
+impl RequiredPermission for CanTeleport {
+  const PERMISSION: user::Permission = user::Permission::Teleport;
+}
+
+pub struct CanBuildWorld;
+impl RequiredPermission for CanBuildWorld {
+  const PERMISSION: user::Permission = user::Permission::BuildWorld;
+}
+
+pub struct CanModerate;
+impl RequiredPermission for CanModerate {
+  const PERMISSION: user::Permission = user::Permission::Moderate;
+}
+
+pub struct CanReloadData;
+impl RequiredPermission for CanReloadData {
+  const PERMISSION: user::Permission = user::Permission::ReloadData;
+}
+
+pub struct CanManageRoles;
+impl RequiredPermission for CanManageRoles {
+  const PERMISSION: user::Permission = user::Permission::ManageRoles;
+}
+
+// request guard for endpoints that require a logged-in user with a permission
+pub struct Authorized<P: RequiredPermission> {
+  pub user: AuthenticatedUser,
+  permission: PhantomData<P>
+}
+
+#[rocket::async_trait]
+impl<'r, P: RequiredPermission + Send> FromRequest<'r> for Authorized<P> {
+  type Error = &'static str;
+
+  async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+    let authenticated_user = match request.guard::<AuthenticatedUser>().await {
+      Outcome::Success(authenticated_user) => authenticated_user,
+      Outcome::Failure(e) => return Outcome::Failure(e),
+      Outcome::Forward(f) => return Outcome::Forward(f)
+    };
+    let users = match request.rocket().state::<user_store::Users>() {
+      Some(users) => users,
+      None => return Outcome::Failure((
+        Status::InternalServerError, "user store is not configured"
+      ))
+    };
+
+    // the role is read fresh so that role changes apply immediately
+    let permitted = match users.get(&authenticated_user.username) {
+      Ok(Some(user)) => user.role.has_permission(P::PERMISSION),
+      _ => false
+    };
+
+    if permitted {
+      Outcome::Success(Authorized {
+        user: authenticated_user,
+        permission: PhantomData
+      })
+    } else {
+      Outcome::Failure((
+        Status::Forbidden, "you do not have permission to use this command"
+      ))
+    }
+  }
+}
@@ -0,0 +1,167 @@
+/**
+ * Auto-walk: players pick a destination and are walked there one step at a
+ * time along the shortest route, as if they had moved by hand, so everyone
+ * along the way sees them arrive and leave. Treasure chests only turn up at
+ * the destination, since a traveler doesn't stop to open any on the way.
+ * A journey ends early if a step can't be taken (e.g. a door has been
+ * locked), if the player moves somewhere else themselves, starts another
+ * journey or logs out.
+ */
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use rocket::tokio::{self, time::{self, Duration}};
+
+use crate::entities;
+use crate::events::{EventBus, GameEvent};
+use crate::game_actions;
+use crate::message;
+use crate::mudnix_utils;
+use crate::user_store;
+use crate::world_map;
+use crate::error::MudnixError;
+
+// the managed state a journey needs after the request that started it is gone
+#[derive(Clone)]
+pub struct Walker {
+  pub users: user_store::Users,
+  pub world: world_map::World,
+  pub chat: message::ChatBus,
+  pub events: EventBus,
+  pub logged_in_user_pool: mudnix_utils::LoggedInUserPool,
+  pub items: entities::ItemRegistry
+}
+
+// tells the traveler, and only them, how their journey is going
+fn notify(username: &str, text: &str, walker: &Walker) {
+  let location_id = walker.world.location_of(username).unwrap_or_default();
+  let message = walker.chat.publish(message::Message {
+    kind: message::MessageKind::System,
+    ..message::Message::direct(text, username, &location_id, username)
+  });
+  walker.events.publish(GameEvent::Chat { message });
+}
+
+/**
+ * Journeys under way, by username. Each journey has a number, so a journey
+ * that has been replaced or stopped notices and ends at its next step.
+ * Clones share the same journeys.
+ */
+#[derive(Clone)]
+pub struct Journeys {
+  current: Arc<Mutex<HashMap<String, u64>>>,
+  next_id: Arc<Mutex<u64>>,
+  step_interval: Duration
+}
+
+impl Journeys {
+  pub fn new(step_seconds: u64) -> Self {
+    Self {
+      current: Arc::new(Mutex::new(HashMap::new())),
+      next_id: Arc::new(Mutex::new(0)),
+      step_interval: Duration::from_secs(step_seconds.max(1))
+    }
+  }
+
+  fn is_current(&self, username: &str, journey_id: u64) -> bool {
+    self.current.lock().unwrap().get(username) == Some(&journey_id)
+  }
+
+  fn finish(&self, username: &str, journey_id: u64) {
+    let mut current = self.current.lock().unwrap();
+    if current.get(username) == Some(&journey_id) {
+      current.remove(username);
+    }
+  }
+
+  // returns true if the user was on a journey
+  pub fn stop(&self, username: &str) -> bool {
+    self.current.lock().unwrap().remove(username).is_some()
+  }
+
+  /* Works out the route to `destination` and sets the user walking along it
+     in the background. Returns the route. */
+  pub fn start(
+    &self,
+    username: &str,
+    destination: &str,
+    walker: Walker
+  ) -> Result<serde_json::Value, MudnixError> {
+    let user = mudnix_utils::touch_user(&walker.users, username)?;
+    // fails for locations that don't exist
+    walker.world.with_location(destination, |location| {
+      location.sublocation_index(&world_map::get_sublocation_from_id(destination)?)
+    })??;
+    let route = match walker.world.find_path(&user.world_location, destination) {
+      Some(route) if !route.is_empty() => route,
+      Some(_) => return Err(MudnixError::BadRequest(String::from("You are already there."))),
+      None => return Err(MudnixError::BadRequest(format!(
+        "There is no way to get to {} from here.",
+        world_map::location_id_to_human_readable(destination)
+      )))
+    };
+
+    let journey_id = {
+      let mut next_id = self.next_id.lock().unwrap();
+      *next_id += 1;
+      *next_id
+    };
+    self.current.lock().unwrap().insert(String::from(username), journey_id);
+    tokio::spawn(self.clone().walk(
+      String::from(username),
+      journey_id,
+      user.world_location.clone(),
+      route.clone(),
+      walker
+    ));
+
+    Ok(serde_json::json!({
+      "username": username,
+      "succeeded": true,
+      "info": format!(
+        "You set off toward {}.",
+        world_map::location_id_to_human_readable(destination)
+      ),
+      "route": route,
+      "seconds_per_step": self.step_interval.as_secs()
+    }))
+  }
+
+  async fn walk(
+    self,
+    username: String,
+    journey_id: u64,
+    mut previous_step: String,
+    route: Vec<String>,
+    walker: Walker
+  ) {
+    let mut interval = time::interval(self.step_interval);
+    interval.tick().await; // the first tick is immediate
+    for step in route.iter() {
+      interval.tick().await;
+      if !self.is_current(&username, journey_id)
+        || !walker.logged_in_user_pool.contains(&username)
+        || walker.world.location_of(&username).as_ref() != Some(&previous_step) {
+        break;
+      }
+      // checks that the step is still legal, e.g. that no door has been locked
+      let is_last_step = Some(step) == route.last();
+      let walk = if is_last_step { game_actions::move_to } else { game_actions::move_through };
+      let moved = walk(
+        &username, step, &walker.users, &walker.world,
+        &walker.chat, &walker.events, &walker.items
+      );
+      if let Err(e) = moved {
+        notify(&username, &format!("You stop walking: {}", e), &walker);
+        break;
+      }
+      previous_step = step.clone();
+      if is_last_step {
+        notify(&username, &format!(
+          "You have arrived at {}.",
+          world_map::location_id_to_human_readable(step)
+        ), &walker);
+      }
+    }
+    self.finish(&username, journey_id);
+  }
+}
@@ -1,19 +1,75 @@
 use std::time::SystemTime;
-use std::fs;
+use std::io;
 use serde::{Serialize, Deserialize};
-use serde_json;
+use crate::mudnix_utils;
+use crate::message::Message;
 use crate::entities::{
   Inventory,
   TreasureChest
 };
 
+// things a user may be allowed to do beyond playing the game
+#[derive(Clone, Copy, PartialEq)]
+pub enum Permission {
+  Teleport,
+  BuildWorld,
+  Moderate,
+  ManageRoles,
+  ReloadData // re-read the map and items while the server is running
+}
+
+#[derive(Serialize, Deserialize, FromFormField, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+  #[default]
+  Player,
+  Builder,
+  Moderator,
+  Admin
+}
+
+impl Role {
+  pub fn has_permission(&self, permission: Permission) -> bool {
+    match self {
+      Role::Player => false,
+      Role::Builder => matches!(
+        permission,
+        Permission::Teleport | Permission::BuildWorld
+      ),
+      Role::Moderator => matches!(
+        permission,
+        Permission::Teleport | Permission::Moderate
+      ),
+      Role::Admin => true
+    }
+  }
+}
+
+fn first_level() -> u32 {
+  1
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct User {
   pub username: String,
-  pub password_hash: String, // SHA-256 hash
+  pub password_hash: String, // argon2id PHC string, or SHA-256 hex for old accounts
+  #[serde(default)]
+  pub role: Role,
   pub inventory: Inventory,
   pub active_treasure_chest: Option<TreasureChest>,
   pub world_location: String,
+  #[serde(default)]
+  pub mailbox: Vec<Message>, // direct messages sent while the user was offline
+  #[serde(default)]
+  pub channels: Vec<String>, // player-created chat channels the user has joined
+  #[serde(default)]
+  pub ignored_users: Vec<String>, // whose messages the user doesn't want to see
+  #[serde(default)]
+  pub muted_until: Option<u64>, // seconds since Unix epoch; set by moderators
+  #[serde(default = "first_level")]
+  pub level: u32,
+  #[serde(default)]
+  pub quest_flags: Vec<String>, // set as the user makes progress in quests
   pub last_activity_timestamp: u64, // seconds since Unix epoch
   pub account_creation_timestamp: u64
 }
@@ -26,19 +82,51 @@ impl User {
     Self {
       username: username.to_string(),
       password_hash: password_hash.to_string(),
+      role: Role::Player,
       inventory: vec![],
       active_treasure_chest: None,
       world_location: world_location.to_string(),
+      mailbox: vec![],
+      channels: vec![],
+      ignored_users: vec![],
+      muted_until: None,
+      level: first_level(),
+      quest_flags: vec![],
       last_activity_timestamp: now,
       account_creation_timestamp: now
     }
   }
 
-  pub fn has_been_logged_in_30_mins(&self) -> bool {
-    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
-      .unwrap().as_secs();
+  pub fn has_password(&self, password: &str) -> bool {
+    mudnix_utils::verify_password(password, &self.password_hash)
+  }
 
-    now - self.last_activity_timestamp >= 1800_u64
+  /* Replaces a legacy SHA-256 hash with an argon2 hash. Must only be called
+     with a password that has already been verified. */
+  pub fn rehash_password_if_legacy(&mut self, password: &str) {
+    if mudnix_utils::is_legacy_password_hash(&self.password_hash) {
+      self.password_hash = mudnix_utils::hash_password(password);
+    }
+  }
+
+  pub fn is_muted(&self) -> bool {
+    match self.muted_until {
+      Some(muted_until) => {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+          .unwrap().as_secs();
+        now < muted_until
+      },
+      None => false
+    }
+  }
+
+  pub fn is_ignoring(&self, username: &str) -> bool {
+    self.ignored_users.iter().any(|ignored| ignored == username)
+  }
+
+  pub fn update_timestamp(&mut self) {
+    self.last_activity_timestamp = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
   }
 }
 
@@ -58,30 +146,8 @@ impl UserList {
     self.users.iter().any(|user| user.username == username)
   }
 
-  pub fn get_index_if_valid_creds(
-    &self,
-    username: &str,
-    password_hash: &str
-  ) -> Option<usize> {
-    self.users.iter().position(|user|
-      user.username == username && user.password_hash == password_hash 
-    )
-  }
-
-  pub fn update_timestamp_of_index(&mut self, i: usize) {
-    self.users[i].last_activity_timestamp = SystemTime::now()
-      .duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-  }
-
-  pub fn from_file(file_path: &str) -> Self {
-    let original_json = fs::read_to_string(file_path).unwrap();
-    serde_json::from_str(&original_json).expect("unable to parse json")
-  }
-
-  pub fn save_to_file(&self, output_file_path: &str) {
-    let output_json = serde_json::to_string_pretty(self).unwrap();
-    fs::write(output_file_path, output_json)
-      .expect("unable to save user");
+  pub fn from_file(file_path: &str) -> Result<Self, io::Error> {
+    mudnix_utils::read_json_with_backup(file_path)
   }
 
   pub fn remove_user_if_exists(&mut self, username: &str) {
@@ -1,223 +1,353 @@
 /* API endpoints for user account management and info. */
+use std::time::SystemTime;
 use rocket::State;
+use rocket::form::Form;
+use rocket::http::{Cookie, CookieJar, SameSite};
 use rocket::response::content;
+use rocket::futures::stream::Stream;
 use rocket::response::stream::{Event, EventStream};
 use rocket::tokio::time::{self, Duration};
 use serde_json;
 
 use crate::user;
+use crate::config;
+use crate::entities;
+use crate::user_store;
 use crate::world_map;
 use crate::mudnix_utils;
+use crate::session;
+use crate::events;
+use crate::message;
+use crate::game_actions;
+use crate::error::{MudnixError, JsonResult};
 
-#[post("/new-user?<username>&<password>")]
+/* Credentials are sent as a form in the request body rather than in the
+   query string so that passwords don't end up in access logs. */
+#[derive(FromForm)]
+pub struct Credentials<'r> {
+  pub username: &'r str,
+  pub password: &'r str
+}
+
+#[post("/new-user", data = "<credentials>")]
 pub fn new_user(
-  username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> String {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-
-  if user_list.contains(username) {
-    format!("User {} already exists.", username)
-  } else {
-    let password_hash = mudnix_utils::hash(password);
-    let user = user::User::new(
-      username,
-      &password_hash,
-      "Quux_Plains::northern_region"
-    );
-    user_list.users.push(user);
-    user_list.save_to_file(users_file_path);
+  credentials: Form<Credentials<'_>>,
+  users: &State<user_store::Users>,
+  config: &State<config::MudnixConfig>
+) -> JsonResult {
+  let username = credentials.username;
+  let password_hash = mudnix_utils::hash_password(credentials.password);
+  let user = user::User::new(
+    username,
+    &password_hash,
+    &config.spawn_location
+  );
 
-    format!(
+  if !users.insert(&user)? {
+    return Err(MudnixError::UserExists(String::from(username)));
+  }
+  Ok(content::Json(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "info": format!(
       "New user {} created. Save your password - it can't be recovered!",
       username
     )
-  }
+  }).to_string()))
 }
 
-#[get("/login?<username>&<password>")]
+// request guards and managed state are all passed as arguments
+#[allow(clippy::too_many_arguments)]
+#[post("/login", data = "<credentials>")]
 pub fn login(
-  username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>,
-  logged_in_user_pool: &State<mudnix_utils::LoggedInUserPool>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-
-  let password_hash = mudnix_utils::hash(password);
-
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    /* add the user to the pool of logged-in users if their credentials are valid
-       and they aren't already in the pool */
-    let mut pool = logged_in_user_pool.user_list_mutex.lock().unwrap();
-    let already_logged_in = pool.contains(username);
-
-    user_list.update_timestamp_of_index(i);
-    if !already_logged_in {
-      pool.users.push(
-        user_list.users[i].clone()
-      );
-    }
+  credentials: Form<Credentials<'_>>,
+  cookies: &CookieJar<'_>,
+  users: &State<user_store::Users>,
+  logged_in_user_pool: &State<mudnix_utils::LoggedInUserPool>,
+  sessions: &State<session::SessionStore>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>,
+  config: &State<config::MudnixConfig>
+) -> JsonResult {
+  let username = credentials.username;
+  let valid_creds = match users.get(username)? {
+    Some(user) => user.has_password(credentials.password),
+    None => false
+  };
+  if !valid_creds {
+    return Err(MudnixError::InvalidCredentials);
+  }
 
-    // save the user list with the updated timestamp
-    user_list.save_to_file(users_file_path);
+  /* update the timestamp (and password hash) of the user, and collect
+     any messages that were sent to them while they were offline */
+  let mut mail: Vec<message::Message> = vec![];
+  let user = mudnix_utils::update_user(users, username, &mut |user| {
+    user.update_timestamp();
+    user.rehash_password_if_legacy(credentials.password);
+    mail.append(&mut user.mailbox);
+  })?;
 
-    // place the user in the appropriate location
-    let world_loc_path = world_map::get_path_from_location_id(
-      &user_list.users[i].world_location
+  for channel in user.channels.iter() {
+    chat.join(username, channel);
+  }
+  chat.set_ignored(username, &user.ignored_users);
+
+  /* place the user in the appropriate location, or where new players start
+     if it's no longer there (e.g. a torn-down instance) */
+  let (user, response) = match world.place_user(username, &user.world_location) {
+    Ok(response) => (user, response),
+    Err(MudnixError::InvalidLocation(_)) => {
+      let response = world.place_user(username, &config.spawn_location)?;
+      let user = mudnix_utils::update_user(users, username, &mut |user| {
+        user.world_location = config.spawn_location.clone();
+      })?;
+      (user, response)
+    },
+    Err(e) => return Err(e)
+  };
+
+  /* add the user to the pool of logged-in users if their credentials are valid
+     and they aren't already in the pool */
+  let mut pool = logged_in_user_pool.user_list_mutex.lock().unwrap();
+  let already_logged_in = pool.contains(username);
+  if !already_logged_in {
+    pool.users.push(user.clone());
+    events.publish(events::GameEvent::Arrival {
+      username: String::from(username),
+      location_id: user.world_location.clone()
+    });
+    game_actions::announce(
+      message::MessageKind::Arrival,
+      &format!("{} has logged in.", username),
+      username, &user.world_location, chat, events
     );
-    let mut world_loc = match world_map::WorldLocation::from_file(&world_loc_path) {
-      Ok(world_location) => world_location,
-      Err(_) => return content::Json(serde_json::json!({
-        "username": username,
-        "logged_in": false,
-        "err": format!(
-          "nonexistent location {} found in user save file when attempting to log in",
-          &user_list.users[i].world_location
-        )
-      }).to_string())
-    };
-    let response = match world_loc.move_user_to_self(
-      username,
-      &user_list.users[i].world_location
-    ) {
-      Ok(r) => r,
-      Err(_) => return content::Json(serde_json::json!({
-        "username": username,
-        "logged_in": false,
-        "err": "invalid location in user file"
-      }).to_string())
-    };
-    world_loc.save_to_file(&world_loc_path);
-
-    content::Json(serde_json::json!({
-      "username": username,
-      "logged_in": true,
-      "info": response,
-      "was_previously_logged_in": already_logged_in
-    }).to_string())
-  } else {
-    content::Json(serde_json::json!({
-      "username": username,
-      "logged_in": false,
-      "err": "invalid credentials"
-    }).to_string())
   }
+
+  /* Browsers send a Lax cookie along when another site links here, but only
+     with GET requests, so every route that changes anything is a POST. */
+  let token = sessions.create(username);
+  cookies.add(
+    Cookie::build(session::SESSION_COOKIE, token.clone())
+      .http_only(true)
+      .same_site(SameSite::Lax)
+      .finish()
+  );
+
+  Ok(content::Json(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "token": token,
+    "info": response,
+    "was_previously_logged_in": already_logged_in,
+    "mail": mail
+  }).to_string()))
 }
 
-#[get("/logout?<username>&<password>")]
-pub fn logout(
+/* Removes the user from the world and from the pool of logged-in users.
+   Shared by `logout` and `autologout`. */
+fn remove_user_from_game(
   username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>,
-  logged_in_user_pool: &State<mudnix_utils::LoggedInUserPool>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-
-  let password_hash = mudnix_utils::hash(password);
-
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    /* remove the user from the pool of logged-in users if their credentials
-       are valid and they are in the pool */
-    let mut pool = logged_in_user_pool.user_list_mutex.lock().unwrap();
-
-    // remove the user from the world
-    let world_loc_path = world_map::get_path_from_location_id(
-      &user_list.users[i].world_location
+  users: &user_store::Users,
+  logged_in_user_pool: &mudnix_utils::LoggedInUserPool,
+  world: &world_map::World,
+  chat: &message::ChatBus,
+  events: &events::EventBus
+) -> Result<(), MudnixError> {
+  if let Some(location_id) = world.location_of(username) {
+    events.publish(events::GameEvent::Departure {
+      username: String::from(username),
+      location_id: location_id.clone()
+    });
+    game_actions::announce(
+      message::MessageKind::Departure,
+      &format!("{} has logged out.", username),
+      username, &location_id, chat, events
     );
-    let mut world_loc = match world_map::WorldLocation::from_file(&world_loc_path) {
-      Ok(world_location) => world_location,
-      Err(_) => return content::Json(serde_json::json!({
-        "username": username,
-        "logged_out": false,
-        "err": format!(
-          "nonexistent location {} found in user save file when attempting to log out",
-          &user_list.users[i].world_location
-        )
-      }).to_string())
-    };
-    world_loc.remove_user(username);
-    world_loc.save_to_file(&world_loc_path);
-
-    user_list.update_timestamp_of_index(i);
-    pool.remove_user_if_exists(username);
-    user_list.save_to_file(users_file_path);
-
-    content::Json(serde_json::json!({
-      "username": username,
-      "logged_out": true
-    }).to_string())
-  } else {
-    content::Json(serde_json::json!({
-      "username": username,
-      "logged_out": false,
-      "err": "invalid credentials"
-    }).to_string())
   }
+  // instances don't outlast their party, so anyone leaving one goes back out
+  let origin = world.location_of(username)
+    .and_then(|location_id| world.instance_origin(&location_id));
+  world.remove_user(username);
+  chat.forget(username);
+  logged_in_user_pool.user_list_mutex.lock().unwrap()
+    .remove_user_if_exists(username);
+  mudnix_utils::update_user(users, username, &mut |user| {
+    if let Some(origin) = &origin {
+      user.world_location = origin.clone();
+    }
+    user.update_timestamp();
+  })?;
+  Ok(())
 }
 
-#[get("/autologout?<username>&<password>")]
-pub fn autologout(
-  username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> EventStream![] {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-
-  // we make this copy so we don't have to put the borrow inside the EventStream
-  let username_copy = String::from(username);
-
-  let password_hash = mudnix_utils::hash(password);
+#[allow(clippy::too_many_arguments)]
+#[post("/logout")]
+pub fn logout(
+  player: session::AuthenticatedUser,
+  cookies: &CookieJar<'_>,
+  users: &State<user_store::Users>,
+  logged_in_user_pool: &State<mudnix_utils::LoggedInUserPool>,
+  sessions: &State<session::SessionStore>,
+  world: &State<world_map::World>,
+  chat: &State<message::ChatBus>,
+  events: &State<events::EventBus>
+) -> JsonResult {
+  // the session is revoked even if the user can't be cleanly removed from the world
+  sessions.revoke(&player.token);
+  cookies.remove(Cookie::named(session::SESSION_COOKIE));
 
-  // we copy the file path here, but it's fine because we are only reading the file, not writing it
-  let users_file_path_copy: String = String::from(users_file_path);
+  remove_user_from_game(&player.username, users, logged_in_user_pool, world, chat, events)?;
+  Ok(content::Json(serde_json::json!({
+    "username": player.username,
+    "succeeded": true
+  }).to_string()))
+}
 
-  // run every 10 minutes
-  let mut interval = time::interval(Duration::from_secs(600));
+#[get("/autologout")]
+pub fn autologout<'a>(
+  player: session::AuthenticatedUser,
+  users: &'a State<user_store::Users>,
+  logged_in_user_pool: &'a State<mudnix_utils::LoggedInUserPool>,
+  sessions: &'a State<session::SessionStore>,
+  world: &'a State<world_map::World>,
+  chat: &'a State<message::ChatBus>,
+  events: &'a State<events::EventBus>
+) -> EventStream<impl Stream<Item = Event> + 'a> {
+  // check once a minute whether the session has expired
+  let mut interval = time::interval(Duration::from_secs(60));
 
   EventStream! {
     loop {
       interval.tick().await;
-      let fresh_user_list = user::UserList::from_file(&users_file_path_copy);
-      let i = fresh_user_list.get_index_if_valid_creds(&username_copy, &password_hash).unwrap();
-      if fresh_user_list.users[i].has_been_logged_in_30_mins() {
+      if !sessions.is_active(&player.token) {
+        sessions.revoke(&player.token);
+        // a newer session of the same user keeps them in the game
+        if !sessions.has_active_session(&player.username) {
+          let _ = remove_user_from_game(
+            &player.username, users, logged_in_user_pool, world, chat, events
+          );
+        }
         yield Event::data(serde_json::json!({
-          "username": username_copy,
+          "username": player.username,
           "succeeded": true,
           "info": "logout"
         }).to_string());
+        break;
       }
     }
   }
 }
 
-#[get("/inventory?<username>&<password>")]
+#[get("/inventory")]
 pub fn inventory(
+  player: session::AuthenticatedUser,
+  users: &State<user_store::Users>,
+  items: &State<entities::ItemRegistry>
+) -> JsonResult {
+  let username: &str = &player.username;
+  let user = mudnix_utils::touch_user(users, username)?;
+  Ok(content::Json(serde_json::json!({
+    "username": username,
+    "succeeded": true,
+    "inventory": items.describe_all(&user.inventory)
+  }).to_string()))
+}
+
+#[post("/set-role?<username>&<role>")]
+pub fn set_role(
+  admin: session::Authorized<session::CanManageRoles>,
   username: &str,
-  password: &str,
-  users_file_path_mutex: &State<mudnix_utils::UsersFileMutex>
-) -> content::Json<String> {
-  let users_file_path: &str = &users_file_path_mutex.mutex
-    .lock().unwrap().to_string();
-  let mut user_list = user::UserList::from_file(users_file_path);
-  let password_hash = mudnix_utils::hash(password);
-  if let Some(i) = user_list.get_index_if_valid_creds(username, &password_hash) {
-    user_list.update_timestamp_of_index(i);
-    user_list.save_to_file(users_file_path);
-    content::Json(serde_json::json!({
-      "username": username,
-      "succeeded": true,
-      "inventory": user_list.users[i].inventory
-    }).to_string())
-  } else {
-    mudnix_utils::error_response(username, "invalid credentials")
+  role: user::Role,
+  users: &State<user_store::Users>
+) -> JsonResult {
+  mudnix_utils::update_user(users, username, &mut |user| user.role = role)?;
+  Ok(content::Json(serde_json::json!({
+    "username": admin.user.username,
+    "succeeded": true,
+    "info": format!("changed the role of {}", username),
+    "role": role
+  }).to_string()))
+}
+
+// hides messages from another user until they are unignored
+#[post("/ignore?<username>")]
+pub fn ignore(
+  player: session::AuthenticatedUser,
+  username: &str,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>
+) -> JsonResult {
+  if username == player.username {
+    return Err(MudnixError::BadRequest(String::from("you can't ignore yourself")));
   }
+  mudnix_utils::get_user(users, username)?;
+  let user = mudnix_utils::update_user(users, &player.username, &mut |user| {
+    if !user.is_ignoring(username) {
+      user.ignored_users.push(String::from(username));
+    }
+    user.update_timestamp();
+  })?;
+  chat.set_ignored(&player.username, &user.ignored_users);
+  Ok(content::Json(serde_json::json!({
+    "username": player.username,
+    "succeeded": true,
+    "info": format!("You are now ignoring {}.", username),
+    "ignored_users": user.ignored_users
+  }).to_string()))
+}
+
+#[post("/unignore?<username>")]
+pub fn unignore(
+  player: session::AuthenticatedUser,
+  username: &str,
+  users: &State<user_store::Users>,
+  chat: &State<message::ChatBus>
+) -> JsonResult {
+  let user = mudnix_utils::update_user(users, &player.username, &mut |user| {
+    user.ignored_users.retain(|ignored| ignored != username);
+    user.update_timestamp();
+  })?;
+  chat.set_ignored(&player.username, &user.ignored_users);
+  Ok(content::Json(serde_json::json!({
+    "username": player.username,
+    "succeeded": true,
+    "info": format!("You are no longer ignoring {}.", username),
+    "ignored_users": user.ignored_users
+  }).to_string()))
+}
+
+// stops a user from sending chat messages for a number of minutes
+#[post("/mute?<username>&<minutes>")]
+pub fn mute(
+  moderator: session::Authorized<session::CanModerate>,
+  username: &str,
+  minutes: u64,
+  users: &State<user_store::Users>
+) -> JsonResult {
+  let muted_until = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap().as_secs() + minutes.saturating_mul(60);
+  mudnix_utils::update_user(users, username, &mut |user| {
+    user.muted_until = Some(muted_until);
+  })?;
+  Ok(content::Json(serde_json::json!({
+    "username": moderator.user.username,
+    "succeeded": true,
+    "info": format!("muted {} for {} minutes", username, minutes),
+    "muted_until": muted_until
+  }).to_string()))
+}
+
+#[post("/unmute?<username>")]
+pub fn unmute(
+  moderator: session::Authorized<session::CanModerate>,
+  username: &str,
+  users: &State<user_store::Users>
+) -> JsonResult {
+  mudnix_utils::update_user(users, username, &mut |user| user.muted_until = None)?;
+  Ok(content::Json(serde_json::json!({
+    "username": moderator.user.username,
+    "succeeded": true,
+    "info": format!("unmuted {}", username)
+  }).to_string()))
 }
@@ -0,0 +1,160 @@
+/**
+ * Storage for user accounts. Endpoints go through the `UserStore` trait so
+ * that reads and updates of a single user are done atomically instead of
+ * loading and rewriting every user on each request.
+ */
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior, params};
+use serde_json;
+
+use crate::user::{User, UserList};
+
+pub trait UserStore: Send + Sync {
+  fn get(&self, username: &str) -> Result<Option<User>, io::Error>;
+
+  // returns false without changing anything if the username is taken
+  fn insert(&self, user: &User) -> Result<bool, io::Error>;
+
+  /* Applies `f` to the user and saves the result in a single transaction.
+     Returns the updated user, or None if there is no such user. */
+  fn update(
+    &self,
+    username: &str,
+    f: &mut dyn FnMut(&mut User)
+  ) -> Result<Option<User>, io::Error>;
+
+  // records activity by the user and returns the updated user
+  fn touch(&self, username: &str) -> Result<Option<User>, io::Error> {
+    self.update(username, &mut |user| user.update_timestamp())
+  }
+}
+
+pub type Users = Arc<dyn UserStore>;
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+  io::Error::other(e)
+}
+
+/* Keeps each user as a row in an SQLite database. The user itself is
+   stored as JSON so that new fields on `User` don't need a migration. */
+pub struct SqliteUserStore {
+  connection: Mutex<Connection>
+}
+
+impl SqliteUserStore {
+  pub fn open(db_path: &str) -> Result<Self, io::Error> {
+    let connection = Connection::open(db_path).map_err(to_io_error)?;
+    connection.execute_batch(
+      "PRAGMA journal_mode = WAL;
+       CREATE TABLE IF NOT EXISTS users (
+         username TEXT PRIMARY KEY NOT NULL,
+         data TEXT NOT NULL
+       );
+       CREATE TABLE IF NOT EXISTS metadata (
+         key TEXT PRIMARY KEY NOT NULL,
+         value TEXT NOT NULL
+       );"
+    ).map_err(to_io_error)?;
+    Ok(Self {
+      connection: Mutex::new(connection)
+    })
+  }
+}
+
+fn user_from_row(data: &str) -> Result<User, io::Error> {
+  serde_json::from_str(data).map_err(to_io_error)
+}
+
+impl UserStore for SqliteUserStore {
+  fn get(&self, username: &str) -> Result<Option<User>, io::Error> {
+    let connection = self.connection.lock().unwrap();
+    let data: Option<String> = connection.query_row(
+      "SELECT data FROM users WHERE username = ?1",
+      params![username],
+      |row| row.get(0)
+    ).optional().map_err(to_io_error)?;
+    match data {
+      Some(data) => Ok(Some(user_from_row(&data)?)),
+      None => Ok(None)
+    }
+  }
+
+  fn insert(&self, user: &User) -> Result<bool, io::Error> {
+    let connection = self.connection.lock().unwrap();
+    let data = serde_json::to_string(user).map_err(to_io_error)?;
+    let inserted = connection.execute(
+      "INSERT OR IGNORE INTO users (username, data) VALUES (?1, ?2)",
+      params![user.username, data]
+    ).map_err(to_io_error)?;
+    Ok(inserted == 1)
+  }
+
+  fn update(
+    &self,
+    username: &str,
+    f: &mut dyn FnMut(&mut User)
+  ) -> Result<Option<User>, io::Error> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection
+      .transaction_with_behavior(TransactionBehavior::Immediate)
+      .map_err(to_io_error)?;
+    let data: Option<String> = transaction.query_row(
+      "SELECT data FROM users WHERE username = ?1",
+      params![username],
+      |row| row.get(0)
+    ).optional().map_err(to_io_error)?;
+    let mut user = match data {
+      Some(data) => user_from_row(&data)?,
+      None => return Ok(None)
+    };
+    f(&mut user);
+    transaction.execute(
+      "UPDATE users SET data = ?2 WHERE username = ?1",
+      params![username, serde_json::to_string(&user).map_err(to_io_error)?]
+    ).map_err(to_io_error)?;
+    transaction.commit().map_err(to_io_error)?;
+    Ok(Some(user))
+  }
+}
+
+// set in the metadata table once users.json has been imported
+const LEGACY_IMPORT_KEY: &str = "legacy_users_imported";
+
+impl SqliteUserStore {
+  /* One-shot importer from the old `users.json` format, run in a single
+     transaction so that an import that fails partway leaves nothing behind
+     and is tried again on the next launch. Users that already exist in the
+     store are left alone. Returns how many users were added, or None if
+     the import had already been done. */
+  pub fn import_legacy_json(&self, json_path: &str) -> Result<Option<usize>, io::Error> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection
+      .transaction_with_behavior(TransactionBehavior::Immediate)
+      .map_err(to_io_error)?;
+    let done: Option<String> = transaction.query_row(
+      "SELECT value FROM metadata WHERE key = ?1",
+      params![LEGACY_IMPORT_KEY],
+      |row| row.get(0)
+    ).optional().map_err(to_io_error)?;
+    if done.is_some() {
+      return Ok(None);
+    }
+    let mut imported = 0;
+    if Path::new(json_path).exists() {
+      for user in UserList::from_file(json_path)?.users.iter() {
+        imported += transaction.execute(
+          "INSERT OR IGNORE INTO users (username, data) VALUES (?1, ?2)",
+          params![user.username, serde_json::to_string(user).map_err(to_io_error)?]
+        ).map_err(to_io_error)?;
+      }
+    }
+    transaction.execute(
+      "INSERT INTO metadata (key, value) VALUES (?1, ?2)",
+      params![LEGACY_IMPORT_KEY, json_path]
+    ).map_err(to_io_error)?;
+    transaction.commit().map_err(to_io_error)?;
+    Ok(Some(imported))
+  }
+}
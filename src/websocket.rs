@@ -0,0 +1,296 @@
+/**
+ * The WebSocket game channel. Each logged-in player may open one socket
+ * (authenticated with the same session token as the REST API, sent as a
+ * bearer header or the session cookie, which browsers send with the
+ * handshake) and then:
+ *
+ *  - send commands as JSON, e.g. `{"command": "move", "to": "Quux_Plains::central_region"}`,
+ *    `{"command": "go", "dir": "north"}`, `{"command": "door", "dir": "north", "action": "open"}`,
+ *    `{"command": "travel", "to": "Foo_Pond::shore"}`, `{"command": "say", "message": "hi"}` (with an optional `channel`),
+ *    `{"command": "emote", "action": "waves"}`,
+ *    `{"command": "whisper", "to": "bob", "message": "hi"}`, `tell` (same fields as `whisper`),
+ *    `{"command": "join", "channel": "traders"}`, `leave`, `channels`, `look` or `take`,
+ *    each of which gets a reply with the same body as the matching REST endpoint
+ *    plus a `command` field;
+ *  - receive events from the game as they happen, each tagged with an `event` field
+ *    (`chat`, `arrival`, `departure` or `chest_spawn`).
+ *
+ * Rocket can't upgrade connections, so the channel listens on its own port
+ * (`websocket_port` in the `[mudnix]` config) and shares Rocket's managed state.
+ */
+use std::net::SocketAddr;
+use std::sync::Arc;
+use rocket::{Rocket, Orbit};
+use rocket::fairing::AdHoc;
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::tokio::{self, net::{TcpListener, TcpStream}};
+use rocket::tokio::sync::broadcast::error::RecvError;
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+use crate::config;
+use crate::entities;
+use crate::events;
+use crate::game_actions;
+use crate::message;
+use crate::mudnix_utils;
+use crate::session;
+use crate::travel;
+use crate::user_store;
+use crate::world_map;
+use crate::error::MudnixError;
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+  Move { to: String },
+  Go { dir: world_map::Direction },
+  Door { dir: world_map::Direction, action: world_map::DoorAction },
+  Travel { to: String },
+  Say { message: String, channel: Option<String> },
+  Emote { action: String },
+  Whisper { to: String, message: String },
+  Tell { to: String, message: String },
+  Join { channel: String },
+  Leave { channel: String },
+  Channels,
+  Look,
+  Take
+}
+
+impl Command {
+  fn name(&self) -> &'static str {
+    match self {
+      Command::Move { .. } => "move",
+      Command::Go { .. } => "go",
+      Command::Door { .. } => "door",
+      Command::Travel { .. } => "travel",
+      Command::Say { .. } => "say",
+      Command::Emote { .. } => "emote",
+      Command::Whisper { .. } => "whisper",
+      Command::Tell { .. } => "tell",
+      Command::Join { .. } => "join",
+      Command::Leave { .. } => "leave",
+      Command::Channels => "channels",
+      Command::Look => "look",
+      Command::Take => "take"
+    }
+  }
+}
+
+pub struct GameChannel {
+  users: user_store::Users,
+  world: world_map::World,
+  sessions: session::SessionStore,
+  events: events::EventBus,
+  chat: message::ChatBus,
+  logged_in_user_pool: mudnix_utils::LoggedInUserPool,
+  journeys: travel::Journeys,
+  items: entities::ItemRegistry,
+  config: config::MudnixConfig
+}
+
+// the session token from the handshake, looked for in the same places as the REST API
+fn token_from_handshake(request: &Request) -> Option<String> {
+  let headers = request.headers();
+  if let Some(header) = headers.get("Authorization").and_then(|h| h.to_str().ok()) {
+    if let Some(token) = header.strip_prefix("Bearer ") {
+      return Some(String::from(token.trim()));
+    }
+  }
+  // browsers can't set headers on a WebSocket, but they do send the session cookie
+  let cookies = headers.get("Cookie").and_then(|h| h.to_str().ok())?;
+  cookies.split(';').find_map(|cookie| {
+    let (name, value) = cookie.trim().split_once('=')?;
+    if name == session::SESSION_COOKIE {
+      Some(String::from(value))
+    } else {
+      None
+    }
+  })
+}
+
+fn unauthorized() -> ErrorResponse {
+  let mut response = ErrorResponse::new(Some(MudnixError::NotLoggedIn.to_json().to_string()));
+  *response.status_mut() = StatusCode::UNAUTHORIZED;
+  response
+}
+
+impl GameChannel {
+  fn from_rocket(rocket: &Rocket<Orbit>) -> Option<Self> {
+    Some(Self {
+      users: rocket.state::<user_store::Users>()?.clone(),
+      world: rocket.state::<world_map::World>()?.clone(),
+      sessions: rocket.state::<session::SessionStore>()?.clone(),
+      events: rocket.state::<events::EventBus>()?.clone(),
+      chat: rocket.state::<message::ChatBus>()?.clone(),
+      logged_in_user_pool: rocket.state::<mudnix_utils::LoggedInUserPool>()?.clone(),
+      journeys: rocket.state::<travel::Journeys>()?.clone(),
+      items: rocket.state::<entities::ItemRegistry>()?.clone(),
+      config: rocket.state::<config::MudnixConfig>()?.clone()
+    })
+  }
+
+  // starts listening for WebSocket connections once Rocket has launched
+  pub fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("WebSocket game channel", |rocket| Box::pin(async move {
+      let channel = match GameChannel::from_rocket(rocket) {
+        Some(channel) => Arc::new(channel),
+        None => {
+          println!("Unable to start the WebSocket game channel: missing managed state");
+          return;
+        }
+      };
+      let address = SocketAddr::new(rocket.config().address, channel.config.websocket_port);
+      match TcpListener::bind(address).await {
+        Ok(listener) => {
+          println!("WebSocket game channel listening on ws://{}", address);
+          tokio::spawn(channel.listen(listener));
+        },
+        Err(e) => println!("Unable to start the WebSocket game channel on {}: {}", address, e)
+      }
+    }))
+  }
+
+  async fn listen(self: Arc<Self>, listener: TcpListener) {
+    loop {
+      if let Ok((stream, _)) = listener.accept().await {
+        tokio::spawn(self.clone().handle_connection(stream));
+      }
+    }
+  }
+
+  async fn handle_connection(self: Arc<Self>, stream: TcpStream) {
+    let mut token: Option<String> = None;
+    // the error type is fixed by tungstenite
+    #[allow(clippy::result_large_err)]
+    let authenticate = |request: &Request, response: Response| {
+      match token_from_handshake(request) {
+        Some(t) if self.sessions.is_active(&t) => {
+          token = Some(t);
+          Ok(response)
+        },
+        _ => Err(unauthorized())
+      }
+    };
+    let mut socket = match tokio_tungstenite::accept_hdr_async(stream, authenticate).await {
+      Ok(socket) => socket,
+      Err(_) => return
+    };
+    let token = match token {
+      Some(token) => token,
+      None => return
+    };
+    let username = match self.sessions.touch(&token) {
+      Some(username) => username,
+      None => return
+    };
+
+    let mut receiver = self.events.subscribe();
+    loop {
+      let outgoing = tokio::select! {
+        incoming = socket.next() => match incoming {
+          Some(Ok(Message::Text(text))) => {
+            if self.sessions.touch(&token).is_none() {
+              break;
+            }
+            Some(self.run_command(&username, &text))
+          },
+          Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+          // pings are answered by tungstenite itself
+          Some(Ok(_)) => None
+        },
+        event = receiver.recv() => match event {
+          Ok(event) => {
+            if !self.sessions.is_active(&token) {
+              break;
+            }
+            match self.world.location_of(&username) {
+              Some(location_id) if event.is_visible_to(
+                &username,
+                &location_id,
+                &self.chat.scopes_of(&username, Some(&location_id))
+              ) && !self.is_ignored(&username, &event) => {
+                serde_json::to_value(&event).ok()
+              },
+              _ => None
+            }
+          },
+          // a slow client misses some events rather than holding up everyone else
+          Err(RecvError::Lagged(_)) => None,
+          Err(RecvError::Closed) => break
+        }
+      };
+      if let Some(outgoing) = outgoing {
+        if socket.send(Message::Text(outgoing.to_string())).await.is_err() {
+          return;
+        }
+      }
+    }
+    let _ = socket.send(Message::Text(MudnixError::NotLoggedIn.to_json().to_string())).await;
+    let _ = socket.close(None).await;
+  }
+
+  // whether the event is a message from someone the player is ignoring
+  fn is_ignored(&self, username: &str, event: &events::GameEvent) -> bool {
+    match event {
+      events::GameEvent::Chat { message } => self.chat.is_ignoring(username, &message.user),
+      _ => false
+    }
+  }
+
+  fn run_command(&self, username: &str, text: &str) -> serde_json::Value {
+    let command: Command = match serde_json::from_str(text) {
+      Ok(command) => command,
+      Err(e) => return MudnixError::BadRequest(format!("invalid command: {}", e)).to_json()
+    };
+    let result = match &command {
+      Command::Move { to } => game_actions::move_to(
+        username, to, &self.users, &self.world, &self.chat, &self.events, &self.items
+      ),
+      Command::Go { dir } => game_actions::move_direction(
+        username, *dir, &self.users, &self.world, &self.chat, &self.events, &self.items
+      ),
+      Command::Door { dir, action } => game_actions::door(
+        username, *dir, *action, &self.users, &self.world, &self.chat, &self.events
+      ),
+      Command::Travel { to } => self.journeys.start(username, to, travel::Walker {
+        users: self.users.clone(),
+        world: self.world.clone(),
+        chat: self.chat.clone(),
+        events: self.events.clone(),
+        logged_in_user_pool: self.logged_in_user_pool.clone(),
+        items: self.items.clone()
+      }),
+      Command::Say { message, channel } => game_actions::say(
+        username, message, channel.as_deref(), &self.users, &self.chat, &self.events
+      ),
+      Command::Emote { action } => game_actions::emote(
+        username, action, &self.users, &self.chat, &self.events
+      ),
+      Command::Whisper { to, message } => game_actions::whisper(
+        username, to, message, &self.users, &self.world, &self.chat, &self.events
+      ),
+      Command::Tell { to, message } => game_actions::tell(
+        username, to, message, &self.users, &self.logged_in_user_pool, &self.chat, &self.events
+      ),
+      Command::Join { channel } => game_actions::join_channel(
+        username, channel, &self.users, &self.chat
+      ),
+      Command::Leave { channel } => game_actions::leave_channel(
+        username, channel, &self.users, &self.chat
+      ),
+      Command::Channels => game_actions::list_channels(username, &self.users, &self.chat),
+      Command::Look => game_actions::look(username, &self.users, &self.world, &self.items),
+      Command::Take => game_actions::take(username, &self.users, &self.chat, &self.events)
+    };
+    let mut reply = match result {
+      Ok(reply) => reply,
+      Err(e) => e.to_json()
+    };
+    reply["command"] = serde_json::json!(command.name());
+    reply
+  }
+}
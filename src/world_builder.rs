@@ -0,0 +1,469 @@
+/**
+ * Editing the world map from inside the game, so that builders don't have
+ * to copy `map/template.json` and edit JSON on the server. Each edit is
+ * tried out on a copy of the running world and checked with
+ * `world_validation`. If it holds together, it is also made to the map
+ * files, and the WorldLocations it changed are written back to their
+ * files, which are named after them (e.g. `map/Foo_Pond.json`), before
+ * only those locations are swapped into the world. Who is standing where,
+ * doors and items on the ground are left as they are.
+ */
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::config;
+use crate::mudnix_utils;
+use crate::world_validation::{self, Problem};
+use crate::world_map::{
+  get_parent_location_from_id,
+  get_sublocation_from_id,
+  Biome,
+  Description,
+  Direction,
+  Exit,
+  SubLocation,
+  World,
+  WorldLocation,
+  WorldLocationAttrs
+};
+use crate::error::MudnixError;
+
+pub type Map = HashMap<String, WorldLocation>;
+
+#[derive(Clone)]
+pub struct WorldBuilder {
+  map_dir: String,
+  spawn_location: String,
+  // one edit at a time, so builders can't undo each other's changes
+  lock: Arc<Mutex<()>>
+}
+
+impl WorldBuilder {
+  pub fn new(config: &config::MudnixConfig) -> Self {
+    Self {
+      map_dir: config.map_dir.clone(),
+      spawn_location: config.spawn_location.clone(),
+      lock: Arc::new(Mutex::new(()))
+    }
+  }
+
+  /* Applies `edit`, which returns the names of the WorldLocations it
+     changed, and returns any warnings. An edit that breaks the map in a new
+     way is refused, except for leaving sublocations unreachable, since a
+     new location can't be reached until it has been linked to the rest.
+     The edit is made twice: to the running world, and to the map files, so
+     that what is only true at runtime (e.g. an open door) isn't saved. */
+  pub fn edit(
+    &self,
+    world: &World,
+    edit: impl Fn(&mut Map) -> Result<Vec<String>, MudnixError>
+  ) -> Result<Vec<String>, MudnixError> {
+    let _guard = self.lock.lock().unwrap();
+    let mut map = world.snapshot();
+    let known_problems: HashSet<String> = world_validation::validate(&map, &self.spawn_location)
+      .iter()
+      .map(|problem| problem.to_string())
+      .collect();
+    let changed = edit(&mut map)?;
+
+    let mut warnings: Vec<String> = vec![];
+    let mut errors: Vec<String> = vec![];
+    for problem in world_validation::validate(&map, &self.spawn_location) {
+      let description = problem.to_string();
+      if known_problems.contains(&description) {
+        continue;
+      }
+      match problem {
+        Problem::Unreachable(_) => warnings.push(description),
+        _ => errors.push(description)
+      }
+    }
+    if !errors.is_empty() {
+      return Err(MudnixError::InvalidData(errors));
+    }
+
+    // files changed by hand since the last reload could make the same edit mean something else
+    let out_of_date = |e: String| MudnixError::InvalidData(vec![format!(
+      "the map files don't match the running world ({}); reload them first", e
+    )]);
+    let mut saved_map = World::read_map(&self.map_dir)?;
+    let saved_changed = edit(&mut saved_map).map_err(|e| out_of_date(e.to_string()))?;
+    let mut files: Vec<(String, String)> = vec![];
+    for name in changed.iter() {
+      let location = match saved_map.get(name) {
+        Some(location) if saved_changed.contains(name) => location,
+        _ => return Err(out_of_date(format!("{} differs", name)))
+      };
+      let json = serde_json::to_string_pretty(location)
+        .map_err(|e| MudnixError::Storage(e.to_string()))?;
+      files.push((self.file_of(name), format!("{}\n", json)));
+    }
+
+    // refused if someone is standing somewhere the edit removed
+    let new_locations = changed.iter().filter_map(|name| map.remove(name)).collect();
+    world.replace_locations(new_locations, || {
+      for (file_path, json) in files.iter() {
+        mudnix_utils::write_atomically_with_backup(file_path, json)?;
+      }
+      Ok(())
+    })?;
+    Ok(warnings)
+  }
+
+  /* Reads the map files back into the running world, under the same lock
+     as `edit` so that an edit can't be swapped in over what was reloaded.
+     Returns how many WorldLocations were read and the problems `validate`
+     found, which only stop the reload if `strict` is set. */
+  pub fn reload(&self, world: &World, strict: bool) -> Result<(usize, Vec<String>), MudnixError> {
+    let _guard = self.lock.lock().unwrap();
+    let new_locations = World::read_map(&self.map_dir)
+      .map_err(|e| MudnixError::InvalidData(vec![e.to_string()]))?;
+    let warnings: Vec<String> = world_validation::validate(&new_locations, &self.spawn_location)
+      .iter()
+      .map(|problem| problem.to_string())
+      .collect();
+    if !warnings.is_empty() && strict {
+      return Err(MudnixError::InvalidData(warnings));
+    }
+    let location_count = new_locations.len();
+    world.replace_map(new_locations)?;
+    Ok((location_count, warnings))
+  }
+
+  fn file_of(&self, name: &str) -> String {
+    format!("{}", Path::new(&self.map_dir).join(format!("{}.json", name)).display())
+  }
+
+  /* A new WorldLocation with no sublocations yet. Fails if there's already
+     a location, or a file, by that name. */
+  pub fn create_location(
+    &self,
+    map: &mut Map,
+    name: &str,
+    biome: Biome,
+    spawn_rate: f32
+  ) -> Result<Vec<String>, MudnixError> {
+    check_name(name)?;
+    check_spawn_rate(spawn_rate)?;
+    if map.contains_key(name) || Path::new(&self.file_of(name)).exists() {
+      return Err(MudnixError::BadRequest(format!("{} already exists", name)));
+    }
+    map.insert(String::from(name), WorldLocation {
+      name: String::from(name),
+      attrs: WorldLocationAttrs {
+        treasure_chest_spawn_rate: spawn_rate,
+        biome,
+        description: Description::default(),
+        sublocations: vec![]
+      },
+      instance: None
+    });
+    Ok(vec![String::from(name)])
+  }
+}
+
+// names end up in location IDs and file names, so only plain ones are allowed
+fn check_name(name: &str) -> Result<(), MudnixError> {
+  if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+    return Err(MudnixError::BadRequest(format!(
+      "{} is not a valid name; use letters, digits, '_' and '-'", name
+    )));
+  }
+  Ok(())
+}
+
+fn check_spawn_rate(spawn_rate: f32) -> Result<(), MudnixError> {
+  if !(0.0..=1.0).contains(&spawn_rate) {
+    return Err(MudnixError::BadRequest(String::from(
+      "the treasure chest spawn rate must be between 0 and 1"
+    )));
+  }
+  Ok(())
+}
+
+fn location_mut<'a>(map: &'a mut Map, name: &str) -> Result<&'a mut WorldLocation, MudnixError> {
+  map.get_mut(name)
+    .ok_or_else(|| MudnixError::InvalidLocation(format!("{} does not exist", name)))
+}
+
+fn sublocation_mut<'a>(map: &'a mut Map, location_id: &str) -> Result<&'a mut SubLocation, MudnixError> {
+  let sublocation = get_sublocation_from_id(location_id)?;
+  let location = location_mut(map, &get_parent_location_from_id(location_id))?;
+  let index = location.sublocation_index(&sublocation)?;
+  Ok(&mut location.attrs.sublocations[index])
+}
+
+/* Calls `f` on every sublocation in the map with its location ID, and
+   returns the names of the WorldLocations where `f` returned true. */
+fn update_sublocations(map: &mut Map, mut f: impl FnMut(&str, &mut SubLocation) -> bool) -> Vec<String> {
+  let mut changed: Vec<String> = vec![];
+  for (name, location) in map.iter_mut() {
+    for sublocation in location.attrs.sublocations.iter_mut() {
+      let location_id = format!("{}::{}", name, sublocation.name);
+      if f(&location_id, sublocation) && !changed.contains(name) {
+        changed.push(name.clone());
+      }
+    }
+  }
+  changed
+}
+
+pub fn add_sublocation(
+  map: &mut Map,
+  location: &str,
+  name: &str,
+  t: &str,
+  description: &str
+) -> Result<Vec<String>, MudnixError> {
+  check_name(name)?;
+  let world_location = location_mut(map, location)?;
+  if world_location.attrs.sublocations.iter().any(|sublocation| sublocation.name == name) {
+    return Err(MudnixError::BadRequest(format!("{}::{} already exists", location, name)));
+  }
+  world_location.attrs.sublocations.push(SubLocation {
+    name: String::from(name),
+    t: String::from(t),
+    description: Description {
+      text: String::from(description),
+      variants: vec![]
+    },
+    neighbors: vec![],
+    exits: vec![],
+    items: vec![],
+    active_users: vec![],
+    npcs: vec![]
+  });
+  Ok(vec![String::from(location)])
+}
+
+// renames the sublocation and every neighbor and exit that leads to it
+pub fn rename_sublocation(
+  map: &mut Map,
+  location_id: &str,
+  new_name: &str
+) -> Result<Vec<String>, MudnixError> {
+  check_name(new_name)?;
+  let parent = get_parent_location_from_id(location_id);
+  let new_location_id = format!("{}::{}", parent, new_name);
+  if sublocation_mut(map, &new_location_id).is_ok() {
+    return Err(MudnixError::BadRequest(format!("{} already exists", new_location_id)));
+  }
+  sublocation_mut(map, location_id)?.name = String::from(new_name);
+  let mut changed = update_sublocations(map, |_, sublocation| {
+    let mut renamed = false;
+    for neighbor in sublocation.neighbors.iter_mut().filter(|neighbor| *neighbor == location_id) {
+      *neighbor = new_location_id.clone();
+      renamed = true;
+    }
+    for exit in sublocation.exits.iter_mut().filter(|exit| exit.to == location_id) {
+      exit.to = new_location_id.clone();
+      renamed = true;
+    }
+    renamed
+  });
+  if !changed.contains(&parent) {
+    changed.push(parent);
+  }
+  Ok(changed)
+}
+
+// removes the sublocation and every neighbor and exit that leads to it
+pub fn remove_sublocation(map: &mut Map, location_id: &str) -> Result<Vec<String>, MudnixError> {
+  let parent = get_parent_location_from_id(location_id);
+  let sublocation = get_sublocation_from_id(location_id)?;
+  let world_location = location_mut(map, &parent)?;
+  let index = world_location.sublocation_index(&sublocation)?;
+  world_location.attrs.sublocations.remove(index);
+  let mut changed = update_sublocations(map, |_, sublocation| {
+    let before = sublocation.neighbors.len() + sublocation.exits.len();
+    sublocation.neighbors.retain(|neighbor| neighbor != location_id);
+    sublocation.exits.retain(|exit| exit.to != location_id);
+    sublocation.neighbors.len() + sublocation.exits.len() != before
+  });
+  if !changed.contains(&parent) {
+    changed.push(parent);
+  }
+  Ok(changed)
+}
+
+/* Links two sublocations both ways: as neighbors, or, given a direction,
+   with an exit that way from `from` and the opposite way back from `to`. */
+pub fn link(
+  map: &mut Map,
+  from: &str,
+  to: &str,
+  direction: Option<Direction>
+) -> Result<Vec<String>, MudnixError> {
+  if from == to {
+    return Err(MudnixError::BadRequest(String::from("a sublocation can't be linked to itself")));
+  }
+  sublocation_mut(map, to)?; // fails if it doesn't exist
+  let both_ways = [(from, to, direction), (to, from, direction.map(Direction::opposite))];
+  // checked before adding either, so a taken way back leaves the map as it was
+  for (this, _, direction) in both_ways {
+    if let Some(direction) = direction {
+      if sublocation_mut(map, this)?.exit(direction).is_some() {
+        return Err(MudnixError::BadRequest(format!("{} already has an exit {}", this, direction)));
+      }
+    }
+  }
+  for (this, other, direction) in both_ways {
+    let sublocation = sublocation_mut(map, this)?;
+    match direction {
+      Some(direction) => sublocation.exits.push(Exit::new(direction, other)),
+      None if sublocation.neighbors.iter().any(|neighbor| neighbor == other) => {},
+      None => sublocation.neighbors.push(String::from(other))
+    }
+  }
+  let mut changed = vec![get_parent_location_from_id(from)];
+  if !changed.contains(&get_parent_location_from_id(to)) {
+    changed.push(get_parent_location_from_id(to));
+  }
+  Ok(changed)
+}
+
+// removes every neighbor and exit between two sublocations, both ways
+pub fn unlink(map: &mut Map, from: &str, to: &str) -> Result<Vec<String>, MudnixError> {
+  sublocation_mut(map, from)?;
+  sublocation_mut(map, to)?;
+  let changed = update_sublocations(map, |location_id, sublocation| {
+    let other = if location_id == from { to } else if location_id == to { from } else { return false };
+    let before = sublocation.neighbors.len() + sublocation.exits.len();
+    sublocation.neighbors.retain(|neighbor| neighbor != other);
+    sublocation.exits.retain(|exit| exit.to != other);
+    sublocation.neighbors.len() + sublocation.exits.len() != before
+  });
+  if changed.is_empty() {
+    return Err(MudnixError::BadRequest(format!("{} and {} are not linked", from, to)));
+  }
+  Ok(changed)
+}
+
+// sets whichever of the treasure chest spawn rate and the Biome are given
+pub fn set_attrs(
+  map: &mut Map,
+  location: &str,
+  spawn_rate: Option<f32>,
+  eco: Option<&str>,
+  urban: Option<bool>
+) -> Result<Vec<String>, MudnixError> {
+  let attrs = &mut location_mut(map, location)?.attrs;
+  if let Some(spawn_rate) = spawn_rate {
+    check_spawn_rate(spawn_rate)?;
+    attrs.treasure_chest_spawn_rate = spawn_rate;
+  }
+  if let Some(eco) = eco {
+    attrs.biome.eco = String::from(eco);
+  }
+  if let Some(urban) = urban {
+    attrs.biome.urban = urban;
+  }
+  Ok(vec![String::from(location)])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::world_map::tests::{location, map, world, Sublocations};
+
+  const PLAINS: Sublocations = &[
+    ("north", &["Plains::south"]),
+    ("south", &["Plains::north", "Pond::shore"])
+  ];
+  const POND: Sublocations = &[("shore", &["Plains::south"])];
+
+  fn plains_and_pond() -> Map {
+    map(vec![location("Plains", PLAINS), location("Pond", POND)])
+  }
+
+  fn sublocation<'a>(map: &'a Map, location_id: &str) -> &'a SubLocation {
+    let location = &map[&get_parent_location_from_id(location_id)];
+    let name = get_sublocation_from_id(location_id).unwrap();
+    location.attrs.sublocations.iter().find(|sublocation| sublocation.name == name).unwrap()
+  }
+
+  fn sorted(mut names: Vec<String>) -> Vec<String> {
+    names.sort();
+    names
+  }
+
+  #[test]
+  fn renaming_rewrites_what_leads_there() {
+    let mut map = plains_and_pond();
+    link(&mut map, "Pond::shore", "Plains::south", Some(Direction::West)).unwrap();
+    let changed = rename_sublocation(&mut map, "Plains::south", "meadow").unwrap();
+    assert_eq!(sorted(changed), vec!["Plains", "Pond"]);
+    assert_eq!(sublocation(&map, "Plains::north").neighbors, vec!["Plains::meadow"]);
+    let shore = sublocation(&map, "Pond::shore");
+    assert_eq!(shore.neighbors, vec!["Plains::meadow"]);
+    assert_eq!(shore.exit(Direction::West).unwrap().to, "Plains::meadow");
+    assert!(world_validation::validate(&map, "Plains::north").is_empty());
+
+    assert!(rename_sublocation(&mut map, "Plains::meadow", "north").is_err());
+  }
+
+  #[test]
+  fn removing_drops_what_leads_there() {
+    let mut map = plains_and_pond();
+    link(&mut map, "Pond::shore", "Plains::south", Some(Direction::West)).unwrap();
+    let changed = remove_sublocation(&mut map, "Plains::south").unwrap();
+    assert_eq!(sorted(changed), vec!["Plains", "Pond"]);
+    assert_eq!(map["Plains"].attrs.sublocations.len(), 1);
+    assert!(sublocation(&map, "Plains::north").neighbors.is_empty());
+    let shore = sublocation(&map, "Pond::shore");
+    assert!(shore.neighbors.is_empty());
+    assert!(shore.exits.is_empty());
+  }
+
+  #[test]
+  fn linking_with_a_direction_adds_the_way_back() {
+    let mut map = plains_and_pond();
+    let changed = link(&mut map, "Plains::north", "Pond::shore", Some(Direction::North)).unwrap();
+    assert_eq!(changed, vec!["Plains", "Pond"]);
+    assert_eq!(sublocation(&map, "Plains::north").exit(Direction::North).unwrap().to, "Pond::shore");
+    assert_eq!(sublocation(&map, "Pond::shore").exit(Direction::South).unwrap().to, "Plains::north");
+
+    assert!(matches!(
+      link(&mut map, "Plains::north", "Plains::south", Some(Direction::North)),
+      Err(MudnixError::BadRequest(_))
+    ));
+    // the way back is checked too, and nothing is added when it is taken
+    assert!(link(&mut map, "Plains::south", "Pond::shore", Some(Direction::North)).is_err());
+    assert!(sublocation(&map, "Plains::south").exit(Direction::North).is_none());
+  }
+
+  #[test]
+  fn unlinking_needs_a_link() {
+    let mut map = plains_and_pond();
+    assert!(matches!(
+      unlink(&mut map, "Plains::north", "Pond::shore"),
+      Err(MudnixError::BadRequest(_))
+    ));
+    assert_eq!(sorted(unlink(&mut map, "Plains::south", "Pond::shore").unwrap()), vec!["Plains", "Pond"]);
+    assert!(sublocation(&map, "Pond::shore").neighbors.is_empty());
+  }
+
+  #[test]
+  fn edit_refuses_new_problems() {
+    let world = world(&[("Plains", PLAINS), ("Pond", POND)]);
+    let builder = WorldBuilder {
+      map_dir: String::from("/nonexistent"),
+      spawn_location: String::from("Plains::north"),
+      lock: Arc::new(Mutex::new(()))
+    };
+    let result = builder.edit(&world, |map| {
+      sublocation_mut(map, "Plains::north")?.neighbors.push(String::from("Pond::shore"));
+      Ok(vec![String::from("Plains")])
+    });
+    let expected = Problem::OneWayNeighbor {
+      from: String::from("Plains::north"),
+      to: String::from("Pond::shore")
+    };
+    match result {
+      Err(MudnixError::InvalidData(errors)) => assert_eq!(errors, vec![expected.to_string()]),
+      other => panic!("expected the edit to be refused, got {:?}", other)
+    }
+    assert_eq!(world.snapshot()["Plains"].attrs.sublocations[0].neighbors, vec!["Plains::south"]);
+  }
+}
@@ -1,90 +1,325 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs;
 use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::SystemTime;
 use serde::{Serialize, Deserialize};
-use serde_json;
 use crate::entities;
+use crate::mudnix_utils;
+use crate::user::User;
+use crate::error::MudnixError;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Biome {
   pub eco: String,
   pub urban: bool
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeOfDay {
+  Morning,
+  Day,
+  Evening,
+  Night
+}
+
+impl TimeOfDay {
+  // from the server's clock, in UTC
+  pub fn now() -> Self {
+    let hour = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap().as_secs() / 3600 % 24;
+    match hour {
+      5..=10 => TimeOfDay::Morning,
+      11..=16 => TimeOfDay::Day,
+      17..=20 => TimeOfDay::Evening,
+      _ => TimeOfDay::Night
+    }
+  }
+}
+
+/* Text used instead of the plain description when its conditions hold,
+   e.g. at night or when the location is urban. Conditions left out always hold. */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DescriptionVariant {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub time_of_day: Option<TimeOfDay>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub eco: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub urban: Option<bool>,
+  pub text: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Description {
+  pub text: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub variants: Vec<DescriptionVariant>
+}
+
+impl Description {
+  // the first variant whose conditions hold, or else the plain text
+  pub fn render(&self, time_of_day: TimeOfDay, biome: &Biome) -> &str {
+    self.variants.iter()
+      .find(|variant| {
+        variant.time_of_day.is_none_or(|t| t == time_of_day)
+          && variant.eco.as_ref().is_none_or(|eco| eco == &biome.eco)
+          && variant.urban.is_none_or(|urban| urban == biome.urban)
+      })
+      .map_or(&self.text, |variant| &variant.text)
+  }
+}
+
+#[derive(Serialize, Deserialize, FromFormField, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+  North,
+  South,
+  East,
+  West,
+  Up,
+  Down
+}
+
+impl Direction {
+  pub fn opposite(self) -> Self {
+    match self {
+      Direction::North => Direction::South,
+      Direction::South => Direction::North,
+      Direction::East => Direction::West,
+      Direction::West => Direction::East,
+      Direction::Up => Direction::Down,
+      Direction::Down => Direction::Up
+    }
+  }
+}
+
+impl fmt::Display for Direction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Direction::North => "north",
+      Direction::South => "south",
+      Direction::East => "east",
+      Direction::West => "west",
+      Direction::Up => "up",
+      Direction::Down => "down"
+    };
+    write!(f, "{}", name)
+  }
+}
+
+fn default_door_name() -> String {
+  String::from("door")
+}
+
+/* A door across an exit, which players can open and close, and lock and
+   unlock if they hold its key. The exit on the other side should have a
+   door too; both are kept in the same state. */
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Door {
+  #[serde(default = "default_door_name")]
+  pub name: String, // e.g. "gate", as in "The gate is closed."
+  #[serde(default)]
+  pub open: bool,
+  #[serde(default)]
+  pub locked: bool,
+  // the `t` of the Item that locks and unlocks it; without one it has no lock
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub key: Option<String>
+}
+
+#[derive(Serialize, Deserialize, FromFormField, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DoorAction {
+  Open,
+  Close,
+  Lock,
+  Unlock
+}
+
+impl Door {
+  // changes the door's state, or says why the user can't
+  pub fn apply(&mut self, action: DoorAction, user: &User) -> Result<(), String> {
+    let has_key = self.key.as_ref()
+      .is_some_and(|key| user.inventory.iter().any(|item| &item.t == key));
+    match action {
+      DoorAction::Open if self.locked => Err(format!("The {} is locked.", self.name)),
+      DoorAction::Open => {
+        self.open = true;
+        Ok(())
+      },
+      DoorAction::Close => {
+        self.open = false;
+        Ok(())
+      },
+      DoorAction::Lock | DoorAction::Unlock if self.key.is_none() => {
+        Err(format!("The {} has no lock.", self.name))
+      },
+      DoorAction::Lock | DoorAction::Unlock if !has_key => {
+        Err(format!("You don't have the key to the {}.", self.name))
+      },
+      DoorAction::Lock if self.open => Err(format!("You have to close the {} first.", self.name)),
+      DoorAction::Lock | DoorAction::Unlock => {
+        self.locked = action == DoorAction::Lock;
+        Ok(())
+      }
+    }
+  }
+}
+
+// a way out of a sublocation, e.g. north to `Quux_Plains::northern_region`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Exit {
+  pub direction: Direction,
+  pub to: String, // location ID
+  // shown to players instead of the destination's name, e.g. "a narrow path"
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub label: Option<String>,
+  // conditions on using the exit; those left out always hold
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub door: Option<Door>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub requires_item: Option<String>, // the `t` of an Item the user must hold
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub min_level: Option<u32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub quest_flag: Option<String>,
+  // said to users who don't meet the item, level or quest conditions
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub refusal: Option<String>
+}
+
+impl Exit {
+  pub fn new(direction: Direction, to: &str) -> Self {
+    Self {
+      direction,
+      to: String::from(to),
+      label: None,
+      door: None,
+      requires_item: None,
+      min_level: None,
+      quest_flag: None,
+      refusal: None
+    }
+  }
+
+  // whether there's a door or some condition on going through the exit, met or not
+  pub fn is_gated(&self) -> bool {
+    self.door.is_some()
+      || self.requires_item.is_some()
+      || self.min_level.is_some()
+      || self.quest_flag.is_some()
+  }
+
+  // whether the user may go through the exit, and if not, why
+  pub fn check(&self, user: &User) -> Result<(), String> {
+    if let Some(door) = self.door.as_ref().filter(|door| !door.open) {
+      return Err(format!("The {} is closed.", door.name));
+    }
+    let refuse = |reason: String| Err(self.refusal.clone().unwrap_or(reason));
+    if let Some(item_type) = &self.requires_item {
+      if !user.inventory.iter().any(|item| &item.t == item_type) {
+        return refuse(format!(
+          "You need a {} to go that way.",
+          item_type.replace('_', " ")
+        ));
+      }
+    }
+    if let Some(min_level) = self.min_level {
+      if user.level < min_level {
+        return refuse(format!("You must be level {} to go that way.", min_level));
+      }
+    }
+    if let Some(flag) = &self.quest_flag {
+      if !user.quest_flags.contains(flag) {
+        return refuse(String::from("You can't go that way yet."));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct SubLocation {
   pub name: String,
   pub t: String,
+  #[serde(default)]
+  pub description: Description,
   pub neighbors: Vec<String>,
+  #[serde(default)]
+  pub exits: Vec<Exit>,
+  // occupancy is only tracked at runtime, never saved with the map
+  #[serde(skip)]
   pub active_users: Vec<String>,
-  pub npcs: Vec<entities::Npc>
+  pub npcs: Vec<entities::Npc>,
+  #[serde(default)]
+  pub items: entities::Inventory // lying on the ground
 }
 
 impl SubLocation {
-  pub fn is_neighbor(&self, location_id: &str) -> bool {
-    self.neighbors.iter().any(|neighbor| neighbor == location_id)
+  // every location this one links to, through its neighbors or its exits
+  pub fn destinations(&self) -> Vec<String> {
+    let mut destinations = self.neighbors.clone();
+    for exit in self.exits.iter() {
+      if !destinations.contains(&exit.to) {
+        destinations.push(exit.to.clone());
+      }
+    }
+    destinations
+  }
+
+  pub fn exit(&self, direction: Direction) -> Option<&Exit> {
+    self.exits.iter().find(|exit| exit.direction == direction)
   }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct WorldLocationAttrs {
   pub treasure_chest_spawn_rate: f32,
   pub biome: Biome,
+  #[serde(default)]
+  pub description: Description,
   pub sublocations: Vec<SubLocation>
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct WorldLocation {
   pub name: String,
-  pub attrs: WorldLocationAttrs
-}
-
-// returned by WorldLocation::move_user_from
-pub struct MovementResult {
-  username: String
+  pub attrs: WorldLocationAttrs,
+  // set on temporary instances, which are never saved with the map
+  #[serde(skip)]
+  pub instance: Option<Instance>
 }
 
-impl MovementResult {
-  pub fn to(&self, dest_location_id: &str) -> Result<String, io::Error> {
-    let mut dest = match WorldLocation::from_location_id(dest_location_id) {
-      Ok(dwl) => dwl,
-      Err(e) => return Err(e)
-    };
-    let result = match dest.move_user_to_self(&self.username, dest_location_id) {
-      Ok(r) => r,
-      Err(e) => return Err(e)
-    };
-    dest.save_to_file(&get_path_from_location_id(dest_location_id));
-    Ok(result)
-  }
+/* A temporary copy of a place (e.g. a dungeon) made for a party of players,
+   which is torn down once the last of them leaves. Only the party can get
+   in; players who are invited join it by accepting. */
+#[derive(Clone)]
+pub struct Instance {
+  pub party: Vec<String>,
+  pub invited: Vec<String>,
+  pub origin: String // where the party came from, and go back to
 }
 
 impl WorldLocation {
   pub fn from_file(file_path: &str) -> Result<Self, io::Error> {
-    let original_json = match fs::read_to_string(file_path) {
-      Ok(file_contents) => file_contents,
-      Err(e) => return Err(e)
-    };
-    Ok(serde_json::from_str(&original_json).expect("unable to parse json"))
-  }
-
-  pub fn from_location_id(location_id: &str) -> Result<Self, io::Error> {
-    Self::from_file(&get_path_from_location_id(location_id))
-  }
-
-  pub fn save_to_file(&self, output_file_path: &str) {
-    let output_json = serde_json::to_string_pretty(self).unwrap();
-    fs::write(output_file_path, output_json)
-      .expect("unable to save world location");
+    mudnix_utils::read_json_with_backup(file_path)
   }
 
   pub fn move_user_to_self(
     &mut self,
     username: &str,
     location_id: &str
-  ) -> Result<String, io::Error> {
+  ) -> Result<String, MudnixError> {
     let loc_parts: Vec<&str> = location_id.split("::").collect();
     if loc_parts.len() == 1 {
-      Err(io::Error::new(io::ErrorKind::InvalidInput, "no sublocation specified"))
+      Err(MudnixError::InvalidLocation(format!(
+        "no sublocation specified for {}",
+        location_id
+      )))
     } else {
       if let Some(i) = self.attrs.sublocations.iter().position(|p| p.name == loc_parts[1]) {
         if !self.attrs.sublocations[i].active_users.iter().any(|user| user == username) {
@@ -95,11 +330,11 @@ impl WorldLocation {
           location_id_to_human_readable(location_id)
         ))
       } else {
-        Ok(format!(
+        Err(MudnixError::InvalidLocation(format!(
           "{}: no such sublocation in {}",
           loc_parts[1].replace("_", " "),
           loc_parts[0].replace("_", " ")
-        ))
+        )))
       }
     }
   }
@@ -113,37 +348,417 @@ impl WorldLocation {
     }
   }
 
-  pub fn move_user_from(&mut self, username: &str, src_location: &str) -> MovementResult {
-    self.remove_user(username);
-    self.save_to_file(&get_path_from_location_id(src_location));
-    MovementResult { username: String::from(username) }
-  }
-
-  pub fn sublocation_index(&self, sublocation_id: &str) -> Result<usize, io::Error> {
+  pub fn sublocation_index(&self, sublocation_id: &str) -> Result<usize, MudnixError> {
     if let Some(i) = self.attrs.sublocations.iter().position(|sl| sl.name == sublocation_id) {
       Ok(i)
     } else {
-      Err(io::Error::new(io::ErrorKind::InvalidInput, "sublocation does not exist"))
+      Err(MudnixError::InvalidLocation(format!(
+        "unable to find the requested sublocation {}",
+        sublocation_id
+      )))
     }
   }
 
   pub fn get_users_from_sublocation(
     &self,
     sublocation_id: &str
-  ) -> Result<Vec<String>, io::Error> {
-    let i = match self.sublocation_index(sublocation_id) {
-      Ok(i) => i,
-      Err(e) => return Err(e)
-    };
+  ) -> Result<Vec<String>, MudnixError> {
+    let i = self.sublocation_index(sublocation_id)?;
     Ok(self.attrs.sublocations[i].active_users.clone())
   }
 }
 
-pub fn get_path_from_location_id(location_id: &str) -> String {
-  format!(
-    "/home/runner/mudnix/map/{}.json",
-    get_parent_location_from_id(location_id)
-  )
+// a starting point for new map files, which isn't part of the world itself
+const TEMPLATE_FILE: &str = "template.json";
+
+/**
+ * The whole game world, loaded from the map directory once at launch and
+ * kept in memory. Who is standing where is tracked here at runtime only.
+ * Clones share the same world, so it can be handed to background tasks.
+ */
+#[derive(Clone)]
+pub struct World {
+  locations: Arc<RwLock<HashMap<String, WorldLocation>>>
+}
+
+impl World {
+  // every WorldLocation in the map directory, by name, leaving out the template
+  pub fn read_map(map_dir: &str) -> Result<HashMap<String, WorldLocation>, io::Error> {
+    let mut locations: HashMap<String, WorldLocation> = HashMap::new();
+    for entry in fs::read_dir(Path::new(map_dir))? {
+      let path = entry?.path();
+      if path.extension().is_some_and(|extension| extension == "json")
+        && path.file_name().is_none_or(|file_name| file_name != TEMPLATE_FILE) {
+        let location = WorldLocation::from_file(&format!("{}", path.display()))
+          .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        locations.insert(location.name.clone(), location);
+      }
+    }
+    Ok(locations)
+  }
+
+  pub fn load(map_dir: &str) -> Result<Self, io::Error> {
+    Ok(Self {
+      locations: Arc::new(RwLock::new(World::read_map(map_dir)?))
+    })
+  }
+
+  /* Swaps in a freshly read map in one go, so nobody sees half of it.
+     Everyone stays where they are, doors that are still there stay open or
+     locked, and instances are kept. If a location someone is standing in
+     has gone, or a new location has the same name as an instance, nothing
+     is changed and the error lists what is in the way. */
+  pub fn replace_map(
+    &self,
+    mut new_locations: HashMap<String, WorldLocation>
+  ) -> Result<(), MudnixError> {
+    let mut locations = self.locations.write().unwrap();
+    let mut problems: Vec<String> = vec![];
+    for (name, location) in locations.iter() {
+      if location.instance.is_some() {
+        if new_locations.contains_key(name) {
+          problems.push(format!("{} has the same name as an instance that is in use", name));
+        }
+        continue;
+      }
+      problems.extend(carry_over(location, new_locations.get_mut(name)));
+    }
+    if !problems.is_empty() {
+      return Err(MudnixError::InvalidData(problems));
+    }
+    for (name, location) in locations.drain() {
+      if location.instance.is_some() {
+        new_locations.insert(name, location);
+      }
+    }
+    *locations = new_locations;
+    Ok(())
+  }
+
+  // a copy of every WorldLocation that isn't an instance, to try out changes on
+  pub fn snapshot(&self) -> HashMap<String, WorldLocation> {
+    self.locations().iter()
+      .filter(|(_, location)| location.instance.is_none())
+      .map(|(name, location)| (name.clone(), location.clone()))
+      .collect()
+  }
+
+  /* Swaps in changed versions of some WorldLocations, keeping who is
+     standing where, the doors and the items on the ground as they are now.
+     `save` is called first, with the world locked, and nothing is swapped
+     in unless it succeeds. As with `replace_map`, nothing changes if
+     someone would be left nowhere. */
+  pub fn replace_locations(
+    &self,
+    mut new_locations: Vec<WorldLocation>,
+    save: impl FnOnce() -> Result<(), MudnixError>
+  ) -> Result<(), MudnixError> {
+    let mut locations = self.locations.write().unwrap();
+    let mut problems: Vec<String> = vec![];
+    for new_location in new_locations.iter_mut() {
+      let location = match locations.get(&new_location.name) {
+        Some(location) => location,
+        None => continue
+      };
+      if location.instance.is_some() {
+        problems.push(format!("{} has the same name as an instance that is in use", location.name));
+        continue;
+      }
+      problems.extend(carry_over(location, Some(new_location)));
+      for new_sublocation in new_location.attrs.sublocations.iter_mut() {
+        if let Some(sublocation) = location.attrs.sublocations.iter().find(|sl| sl.name == new_sublocation.name) {
+          new_sublocation.items = sublocation.items.clone();
+        }
+      }
+    }
+    if !problems.is_empty() {
+      return Err(MudnixError::InvalidData(problems));
+    }
+    save()?;
+    for new_location in new_locations {
+      locations.insert(new_location.name.clone(), new_location);
+    }
+    Ok(())
+  }
+
+  pub fn locations(&self) -> RwLockReadGuard<'_, HashMap<String, WorldLocation>> {
+    self.locations.read().unwrap()
+  }
+
+  // runs `f` on the WorldLocation that contains the given location ID
+  pub fn with_location<T>(
+    &self,
+    location_id: &str,
+    f: impl FnOnce(&WorldLocation) -> T
+  ) -> Result<T, MudnixError> {
+    match self.locations().get(&get_parent_location_from_id(location_id)) {
+      Some(location) => Ok(f(location)),
+      None => Err(invalid_location(location_id))
+    }
+  }
+
+  /* Calls `f` on the door across the exit in the given direction from
+     `location_id`, to open, close, lock or unlock it, and gives the doors
+     on the other side that lead back the same state. Returns the door and
+     where the exit leads. */
+  pub fn use_door(
+    &self,
+    location_id: &str,
+    direction: Direction,
+    f: impl FnOnce(&mut Door) -> Result<(), String>
+  ) -> Result<(Door, String), MudnixError> {
+    let mut locations = self.locations.write().unwrap();
+    let sublocation_id = get_sublocation_from_id(location_id)?;
+    let location = locations.get_mut(&get_parent_location_from_id(location_id))
+      .ok_or_else(|| invalid_location(location_id))?;
+    let i = location.sublocation_index(&sublocation_id)?;
+    let exit = location.attrs.sublocations[i].exits.iter_mut()
+      .find(|exit| exit.direction == direction)
+      .ok_or_else(|| MudnixError::NoExit(direction.to_string()))?;
+    let door = exit.door.as_mut()
+      .ok_or_else(|| MudnixError::BadRequest(format!("There is no door to the {}.", direction)))?;
+    f(door).map_err(MudnixError::BadRequest)?;
+    let (door, destination) = (door.clone(), exit.to.clone());
+
+    // the other side may be in another WorldLocation
+    let other_sublocation_id = get_sublocation_from_id(&destination).unwrap_or_default();
+    if let Some(other_location) = locations.get_mut(&get_parent_location_from_id(&destination)) {
+      let other_side = other_location.attrs.sublocations.iter_mut()
+        .filter(|sl| sl.name == other_sublocation_id)
+        .flat_map(|sl| sl.exits.iter_mut())
+        .filter(|exit| exit.to == location_id)
+        .filter_map(|exit| exit.door.as_mut());
+      for other_door in other_side {
+        other_door.open = door.open;
+        other_door.locked = door.locked;
+      }
+    }
+    Ok((door, destination))
+  }
+
+  // every location ID that can be walked to from `location_id` in one step
+  pub fn adjacent_to(&self, location_id: &str) -> Vec<String> {
+    adjacent_to(&self.locations(), location_id)
+  }
+
+  /* Moves the user to the given location from wherever they were before.
+     If the destination is invalid the user is left where they were. */
+  pub fn place_user(&self, username: &str, location_id: &str) -> Result<String, MudnixError> {
+    let mut locations = self.locations.write().unwrap();
+    let parent = get_parent_location_from_id(location_id);
+    if !locations.contains_key(&parent) {
+      return Err(invalid_location(location_id));
+    }
+    get_sublocation_from_id(location_id)
+      .and_then(|sublocation| locations[&parent].sublocation_index(&sublocation))?;
+    if let Some(instance) = &locations[&parent].instance {
+      if !instance.party.iter().any(|member| member == username) {
+        return Err(MudnixError::ExitBlocked(format!("{} is not in the party in {}", username, parent)));
+      }
+    }
+
+    for location in locations.values_mut() {
+      location.remove_user(username);
+    }
+    let response = locations.get_mut(&parent).unwrap().move_user_to_self(username, location_id);
+    remove_empty_instances(&mut locations);
+    response
+  }
+
+  pub fn remove_user(&self, username: &str) {
+    let mut locations = self.locations.write().unwrap();
+    for location in locations.values_mut() {
+      location.remove_user(username);
+    }
+    remove_empty_instances(&mut locations);
+  }
+
+  /* Adds a new instance and puts its first party member in at the
+     entrance in one go, so it's never empty for someone else's move to tear
+     down. Returns the description of the entrance. */
+  pub fn add_instance(&self, mut instance: WorldLocation, username: &str) -> Result<String, MudnixError> {
+    let mut locations = self.locations.write().unwrap();
+    if locations.contains_key(&instance.name) {
+      return Err(MudnixError::BadRequest(format!("{} already exists", instance.name)));
+    }
+    let entrance = format!("{}::{}", instance.name, instance.attrs.sublocations[0].name);
+    if let Some(party) = instance.instance.as_mut().map(|instance| &mut instance.party) {
+      party.push(String::from(username));
+    }
+    let response = instance.move_user_to_self(username, &entrance)?;
+    for location in locations.values_mut() {
+      location.remove_user(username);
+    }
+    locations.insert(instance.name.clone(), instance);
+    remove_empty_instances(&mut locations);
+    Ok(response)
+  }
+
+  /* Takes up an invitation into an instance: the user joins its party and
+     is put in at the entrance. Returns the entrance's location ID and description. */
+  pub fn join_instance(&self, username: &str, name: &str) -> Result<(String, String), MudnixError> {
+    let mut locations = self.locations.write().unwrap();
+    let not_invited = || MudnixError::BadRequest(format!("you have not been invited into {}", name));
+    let location = locations.get_mut(name).ok_or_else(not_invited)?;
+    let instance = location.instance.as_mut().ok_or_else(not_invited)?;
+    let index = instance.invited.iter().position(|invited| invited == username)
+      .ok_or_else(not_invited)?;
+    instance.invited.remove(index);
+    instance.party.push(String::from(username));
+    let entrance = format!("{}::{}", name, location.attrs.sublocations[0].name);
+
+    for location in locations.values_mut() {
+      location.remove_user(username);
+    }
+    let response = locations.get_mut(name).unwrap().move_user_to_self(username, &entrance);
+    remove_empty_instances(&mut locations);
+    Ok((entrance, response?))
+  }
+
+  // turns down an invitation into an instance; returns false if there wasn't one
+  pub fn decline_instance(&self, username: &str, name: &str) -> bool {
+    let mut locations = self.locations.write().unwrap();
+    match locations.get_mut(name).and_then(|location| location.instance.as_mut()) {
+      Some(instance) => {
+        let before = instance.invited.len();
+        instance.invited.retain(|invited| invited != username);
+        instance.invited.len() != before
+      },
+      None => false
+    }
+  }
+
+  // where the party in the instance at `location_id` came from, if it is one
+  pub fn instance_origin(&self, location_id: &str) -> Option<String> {
+    self.locations().get(&get_parent_location_from_id(location_id))
+      .and_then(|location| location.instance.as_ref())
+      .map(|instance| instance.origin.clone())
+  }
+
+  // the location ID of wherever the user is standing, if they are in the world
+  pub fn location_of(&self, username: &str) -> Option<String> {
+    for location in self.locations().values() {
+      for sublocation in location.attrs.sublocations.iter() {
+        if sublocation.active_users.iter().any(|user| user == username) {
+          return Some(format!("{}::{}", location.name, sublocation.name));
+        }
+      }
+    }
+    None
+  }
+
+  /* The shortest route between two locations, as the location IDs to walk
+     through after leaving `from`, ending with `to`. Exit conditions aren't
+     considered here; they are checked at each step. */
+  pub fn find_path(&self, from: &str, to: &str) -> Option<Vec<String>> {
+    let locations = self.locations();
+    let mut came_from: HashMap<String, String> = HashMap::new();
+    let mut visited: HashSet<String> = HashSet::from([String::from(from)]);
+    let mut queue: VecDeque<String> = VecDeque::from([String::from(from)]);
+    while let Some(location_id) = queue.pop_front() {
+      if location_id == to {
+        let mut path = vec![location_id];
+        while let Some(previous) = came_from.get(path.last().unwrap()) {
+          path.push(previous.clone());
+        }
+        path.pop(); // `from` itself
+        path.reverse();
+        return Some(path);
+      }
+      for next in adjacent_to(&locations, &location_id) {
+        if visited.insert(next.clone()) {
+          came_from.insert(next.clone(), location_id.clone());
+          queue.push_back(next);
+        }
+      }
+    }
+    None
+  }
+
+  pub fn users_at(&self, location_id: &str) -> Result<Vec<String>, MudnixError> {
+    let sublocation = get_sublocation_from_id(location_id)?;
+    self.with_location(location_id, |location| {
+      location.get_users_from_sublocation(&sublocation)
+    })?
+  }
+}
+
+/* Puts everyone standing in `old_location` in the same sublocation of
+   `new_location`, and keeps its doors as they were. Returns who would be
+   left nowhere. */
+fn carry_over(old_location: &WorldLocation, mut new_location: Option<&mut WorldLocation>) -> Vec<String> {
+  let mut stranded: Vec<String> = vec![];
+  for sublocation in old_location.attrs.sublocations.iter() {
+    let still_there = new_location.as_mut().and_then(|new_location| {
+      new_location.attrs.sublocations.iter_mut().find(|sl| sl.name == sublocation.name)
+    });
+    match still_there {
+      Some(new_sublocation) => {
+        new_sublocation.active_users = sublocation.active_users.clone();
+        carry_over_doors(sublocation, new_sublocation);
+      },
+      None => stranded.extend(sublocation.active_users.iter().map(|username| {
+        format!("{} is in {}::{}", username, old_location.name, sublocation.name)
+      }))
+    }
+  }
+  stranded
+}
+
+// keeps each door that is still there as open or locked as it was
+fn carry_over_doors(old_sublocation: &SubLocation, new_sublocation: &mut SubLocation) {
+  for new_exit in new_sublocation.exits.iter_mut() {
+    let old_door = old_sublocation.exits.iter()
+      .find(|exit| exit.direction == new_exit.direction && exit.to == new_exit.to)
+      .and_then(|exit| exit.door.as_ref());
+    if let (Some(old_door), Some(new_door)) = (old_door, new_exit.door.as_mut()) {
+      new_door.open = old_door.open;
+      new_door.locked = old_door.locked;
+    }
+  }
+}
+
+fn remove_empty_instances(locations: &mut HashMap<String, WorldLocation>) {
+  locations.retain(|_, location| {
+    location.instance.is_none()
+      || location.attrs.sublocations.iter().any(|sl| !sl.active_users.is_empty())
+  });
+}
+
+/* Whether an exit with a door or other conditions leads to the location.
+   Then the only ways in are its exits and neighbors, not walking over from
+   elsewhere in the same WorldLocation, so nobody can get around the gate.
+   A door with a matching one on the other side gates both of the places it
+   joins, so other sublocations have to be linked to them as neighbors. */
+pub fn has_gated_entrance(locations: &HashMap<String, WorldLocation>, location_id: &str) -> bool {
+  locations.values()
+    .flat_map(|location| location.attrs.sublocations.iter())
+    .flat_map(|sublocation| sublocation.exits.iter())
+    .any(|exit| exit.to == location_id && exit.is_gated())
+}
+
+/* Every sublocation of the location it's in, except those with a gated
+   entrance, and the ones it links to. */
+pub fn adjacent_to(locations: &HashMap<String, WorldLocation>, location_id: &str) -> Vec<String> {
+  let parent = get_parent_location_from_id(location_id);
+  let sublocation = get_sublocation_from_id(location_id).unwrap_or_default();
+  let mut adjacent: Vec<String> = vec![];
+  if let Some(location) = locations.get(&parent) {
+    for other in location.attrs.sublocations.iter() {
+      let other_id = format!("{}::{}", parent, other.name);
+      if other.name == sublocation {
+        adjacent.push(other_id);
+        adjacent.extend(other.destinations());
+      } else if !has_gated_entrance(locations, &other_id) {
+        adjacent.push(other_id);
+      }
+    }
+  }
+  adjacent
+}
+
+fn invalid_location(location_id: &str) -> MudnixError {
+  MudnixError::InvalidLocation(format!("invalid location \"{}\"", location_id))
 }
 
 pub fn location_id_to_human_readable(location_id: &str) -> String {
@@ -168,11 +783,200 @@ pub fn get_parent_location_from_id(location_id: &str) -> String {
   String::from(loc_parts[0])
 }
 
-pub fn get_sublocation_from_id(location_id: &str) -> Result<String, io::Error> {
+pub fn get_sublocation_from_id(location_id: &str) -> Result<String, MudnixError> {
   let loc_parts: Vec<&str> = location_id.split("::").collect();
   if loc_parts.len() >= 2 {
     Ok(String::from(loc_parts[1]))
   } else {
-    Err(io::Error::new(io::ErrorKind::InvalidInput, "location id has no sublocation"))
+    Err(MudnixError::InvalidLocation(format!(
+      "location id {} does not contain a sublocation",
+      location_id
+    )))
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  // sublocation names, each with the location IDs of its neighbors
+  pub type Sublocations<'a> = &'a [(&'a str, &'a [&'a str])];
+
+  // a plain WorldLocation whose sublocations list the given neighbors, for tests
+  pub fn location(name: &str, sublocations: Sublocations) -> WorldLocation {
+    serde_json::from_value(serde_json::json!({
+      "name": name,
+      "attrs": {
+        "treasure_chest_spawn_rate": 0.0,
+        "biome": { "eco": "plains", "urban": false },
+        "sublocations": sublocations.iter().map(|(sublocation, neighbors)| serde_json::json!({
+          "name": sublocation,
+          "t": "field",
+          "neighbors": neighbors,
+          "npcs": []
+        })).collect::<Vec<_>>()
+      }
+    })).unwrap()
+  }
+
+  // the WorldLocations by name, as the World keeps them
+  pub fn map(locations: Vec<WorldLocation>) -> HashMap<String, WorldLocation> {
+    locations.into_iter().map(|location| (location.name.clone(), location)).collect()
+  }
+
+  pub fn world(locations: &[(&str, Sublocations)]) -> World {
+    let locations = locations.iter().map(|(name, sublocations)| location(name, sublocations)).collect();
+    World { locations: Arc::new(RwLock::new(map(locations))) }
+  }
+
+  fn door(locked: bool) -> Door {
+    Door {
+      name: String::from("door"),
+      open: false,
+      locked,
+      key: Some(String::from("brass_key"))
+    }
+  }
+
+  /* A House whose hall and kitchen are neighbors, with a locked door
+     between the hall and the closet. */
+  pub fn house() -> World {
+    let mut house = location("House", &[
+      ("hall", &["House::kitchen"]),
+      ("kitchen", &["House::hall"]),
+      ("closet", &[])
+    ]);
+    let sublocations = &mut house.attrs.sublocations;
+    sublocations[0].exits.push(Exit { door: Some(door(true)), ..Exit::new(Direction::North, "House::closet") });
+    sublocations[2].exits.push(Exit { door: Some(door(true)), ..Exit::new(Direction::South, "House::hall") });
+    World { locations: Arc::new(RwLock::new(map(vec![house]))) }
+  }
+
+  fn user_with(items: &[&str]) -> User {
+    let mut user = User::new("alice", "", "House::hall");
+    user.inventory = items.iter().map(|t| crate::entities::Item::new(t, 1)).collect();
+    user
+  }
+
+  #[test]
+  fn exit_conditions_are_checked() {
+    let user = user_with(&[]);
+    let exit = Exit { requires_item: Some(String::from("brass_key")), ..Exit::new(Direction::North, "A::b") };
+    assert_eq!(exit.check(&user), Err(String::from("You need a brass key to go that way.")));
+    assert_eq!(exit.check(&user_with(&["brass_key"])), Ok(()));
+
+    let exit = Exit { min_level: Some(5), ..Exit::new(Direction::North, "A::b") };
+    assert_eq!(exit.check(&user), Err(String::from("You must be level 5 to go that way.")));
+
+    let exit = Exit { quest_flag: Some(String::from("met_the_king")), ..Exit::new(Direction::North, "A::b") };
+    assert_eq!(exit.check(&user), Err(String::from("You can't go that way yet.")));
+    let mut flagged = user_with(&[]);
+    flagged.quest_flags.push(String::from("met_the_king"));
+    assert_eq!(exit.check(&flagged), Ok(()));
+
+    let exit = Exit {
+      min_level: Some(5),
+      refusal: Some(String::from("The guard shakes his head.")),
+      ..Exit::new(Direction::North, "A::b")
+    };
+    assert_eq!(exit.check(&user), Err(String::from("The guard shakes his head.")));
+
+    // a closed door says so, whatever the refusal
+    let exit = Exit {
+      door: Some(door(false)),
+      refusal: Some(String::from("The guard shakes his head.")),
+      ..Exit::new(Direction::North, "A::b")
+    };
+    assert_eq!(exit.check(&user), Err(String::from("The door is closed.")));
+  }
+
+  #[test]
+  fn doors_open_close_lock_and_unlock() {
+    let without_key = user_with(&[]);
+    let with_key = user_with(&["brass_key"]);
+    let mut door = door(false);
+
+    assert_eq!(door.apply(DoorAction::Open, &without_key), Ok(()));
+    assert_eq!(door.apply(DoorAction::Lock, &with_key), Err(String::from("You have to close the door first.")));
+    assert_eq!(door.apply(DoorAction::Close, &without_key), Ok(()));
+    assert_eq!(door.apply(DoorAction::Lock, &without_key), Err(String::from("You don't have the key to the door.")));
+    assert_eq!(door.apply(DoorAction::Lock, &with_key), Ok(()));
+    assert_eq!(door.apply(DoorAction::Open, &with_key), Err(String::from("The door is locked.")));
+    assert_eq!(door.apply(DoorAction::Unlock, &without_key), Err(String::from("You don't have the key to the door.")));
+    assert_eq!(door.apply(DoorAction::Unlock, &with_key), Ok(()));
+    assert_eq!(door.apply(DoorAction::Open, &with_key), Ok(()));
+
+    let mut no_lock = Door { key: None, ..door };
+    no_lock.open = false;
+    assert_eq!(no_lock.apply(DoorAction::Lock, &with_key), Err(String::from("The door has no lock.")));
+  }
+
+  #[test]
+  fn both_sides_of_a_door_stay_the_same() {
+    let world = house();
+    let user = user_with(&["brass_key"]);
+    world.use_door("House::hall", Direction::North, |door| door.apply(DoorAction::Unlock, &user)).unwrap();
+    let (door, destination) = world.use_door("House::hall", Direction::North, |door| {
+      door.apply(DoorAction::Open, &user)
+    }).unwrap();
+    assert!(door.open && !door.locked);
+    assert_eq!(destination, "House::closet");
+    let other_side = world.with_location("House::closet", |location| {
+      location.attrs.sublocations[2].exits[0].door.clone().unwrap()
+    }).unwrap();
+    assert!(other_side.open && !other_side.locked);
+  }
+
+  #[test]
+  fn locked_door_cant_be_walked_around() {
+    let world = house();
+    assert!(!world.adjacent_to("House::kitchen").contains(&String::from("House::closet")));
+    assert!(world.adjacent_to("House::kitchen").contains(&String::from("House::hall")));
+    // the route goes through the door, which is checked when it's taken
+    assert_eq!(
+      world.find_path("House::kitchen", "House::closet"),
+      Some(vec![String::from("House::hall"), String::from("House::closet")])
+    );
+  }
+
+  #[test]
+  fn path_crosses_world_locations() {
+    let world = world(&[
+      ("Plains", &[("north", &["Pond::shore"]), ("south", &[])]),
+      ("Pond", &[("shore", &["Plains::north"]), ("depths", &[])])
+    ]);
+    assert_eq!(
+      world.find_path("Plains::south", "Pond::depths"),
+      Some(vec![
+        String::from("Plains::north"),
+        String::from("Pond::shore"),
+        String::from("Pond::depths")
+      ])
+    );
+  }
+
+  #[test]
+  fn path_is_the_shortest() {
+    let world = world(&[
+      ("A", &[("a", &["B::b", "C::c"])]),
+      ("B", &[("b", &["A::a", "C::c"])]),
+      ("C", &[("c", &["A::a", "B::b"])])
+    ]);
+    assert_eq!(world.find_path("A::a", "C::c"), Some(vec![String::from("C::c")]));
+  }
+
+  #[test]
+  fn path_to_where_you_are_is_empty() {
+    let world = world(&[("Plains", &[("north", &[])])]);
+    assert_eq!(world.find_path("Plains::north", "Plains::north"), Some(vec![]));
+  }
+
+  #[test]
+  fn no_path_to_unlinked_locations() {
+    let world = world(&[
+      ("Plains", &[("north", &[])]),
+      ("Pond", &[("shore", &[])])
+    ]);
+    assert_eq!(world.find_path("Plains::north", "Pond::shore"), None);
   }
 }
@@ -0,0 +1,158 @@
+/**
+ * Checks that the world map hangs together: that every neighbor and exit a
+ * sublocation lists exists, that neighbors list each other, and that every
+ * sublocation can be reached from where new players start. Players may
+ * walk between any two sublocations of the same WorldLocation, so those
+ * count as connected.
+ */
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::world_map::{adjacent_to, WorldLocation};
+
+pub enum Problem {
+  MissingSpawn(String),
+  DuplicateSublocation(String),
+  UnknownNeighbor { from: String, to: String },
+  OneWayNeighbor { from: String, to: String },
+  Unreachable(String)
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Problem::MissingSpawn(location_id) => {
+        write!(f, "the spawn location {} does not exist", location_id)
+      },
+      Problem::DuplicateSublocation(location_id) => {
+        write!(f, "{} is defined more than once", location_id)
+      },
+      Problem::UnknownNeighbor { from, to } => {
+        write!(f, "{} lists {} as a neighbor, but it does not exist", from, to)
+      },
+      Problem::OneWayNeighbor { from, to } => {
+        write!(f, "{} lists {} as a neighbor, but not the other way around", from, to)
+      },
+      Problem::Unreachable(location_id) => {
+        write!(f, "{} can't be reached from the spawn location", location_id)
+      }
+    }
+  }
+}
+
+pub fn validate(
+  locations: &HashMap<String, WorldLocation>,
+  spawn_location: &str
+) -> Vec<Problem> {
+  let mut problems: Vec<Problem> = vec![];
+  let mut location_ids: HashSet<String> = HashSet::new();
+  let mut names: Vec<&String> = locations.keys().collect();
+  names.sort(); // so problems are reported in the same order every time
+
+  for name in names.iter() {
+    for sublocation in locations[*name].attrs.sublocations.iter() {
+      let location_id = format!("{}::{}", name, sublocation.name);
+      if !location_ids.insert(location_id.clone()) {
+        problems.push(Problem::DuplicateSublocation(location_id));
+      }
+    }
+  }
+  if !location_ids.contains(spawn_location) {
+    problems.push(Problem::MissingSpawn(String::from(spawn_location)));
+  }
+
+  for name in names.iter() {
+    for sublocation in locations[*name].attrs.sublocations.iter() {
+      let from = format!("{}::{}", name, sublocation.name);
+      for to in sublocation.destinations().iter() {
+        if !location_ids.contains(to) {
+          problems.push(Problem::UnknownNeighbor { from: from.clone(), to: to.clone() });
+        } else if !adjacent_to(locations, to).contains(&from) {
+          problems.push(Problem::OneWayNeighbor { from: from.clone(), to: to.clone() });
+        }
+      }
+    }
+  }
+
+  // breadth-first search from the spawn location over every legal move
+  if location_ids.contains(spawn_location) {
+    let mut reached: HashSet<String> = HashSet::from([String::from(spawn_location)]);
+    let mut queue: VecDeque<String> = VecDeque::from([String::from(spawn_location)]);
+    while let Some(location_id) = queue.pop_front() {
+      for next in adjacent_to(locations, &location_id) {
+        if location_ids.contains(&next) && reached.insert(next.clone()) {
+          queue.push_back(next);
+        }
+      }
+    }
+    let mut unreachable: Vec<&String> = location_ids.difference(&reached).collect();
+    unreachable.sort();
+    problems.extend(unreachable.into_iter().cloned().map(Problem::Unreachable));
+  }
+  problems
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::world_map::tests::{location, map};
+
+  fn problems(locations: &HashMap<String, WorldLocation>, spawn_location: &str) -> Vec<String> {
+    validate(locations, spawn_location).iter().map(|problem| problem.to_string()).collect()
+  }
+
+  #[test]
+  fn linked_map_has_no_problems() {
+    let locations = map(vec![
+      location("Plains", &[("north", &["Pond::shore"]), ("south", &[])]),
+      location("Pond", &[("shore", &["Plains::north"])])
+    ]);
+    assert!(problems(&locations, "Plains::north").is_empty());
+  }
+
+  #[test]
+  fn missing_spawn_is_reported() {
+    let locations = map(vec![location("Plains", &[("north", &[])])]);
+    assert_eq!(problems(&locations, "Plains::south"), vec![
+      Problem::MissingSpawn(String::from("Plains::south")).to_string()
+    ]);
+  }
+
+  #[test]
+  fn bad_neighbors_are_reported() {
+    let locations = map(vec![
+      location("Plains", &[("north", &["Pond::shore", "Pond::depths"])]),
+      location("Pond", &[("shore", &[])])
+    ]);
+    assert_eq!(problems(&locations, "Plains::north"), vec![
+      Problem::OneWayNeighbor {
+        from: String::from("Plains::north"),
+        to: String::from("Pond::shore")
+      }.to_string(),
+      Problem::UnknownNeighbor {
+        from: String::from("Plains::north"),
+        to: String::from("Pond::depths")
+      }.to_string()
+    ]);
+  }
+
+  #[test]
+  fn unlinked_locations_are_unreachable() {
+    let locations = map(vec![
+      location("Plains", &[("north", &[])]),
+      location("Pond", &[("shore", &[]), ("depths", &[])])
+    ]);
+    assert_eq!(problems(&locations, "Plains::north"), vec![
+      Problem::Unreachable(String::from("Pond::depths")).to_string(),
+      Problem::Unreachable(String::from("Pond::shore")).to_string()
+    ]);
+  }
+
+  #[test]
+  fn duplicate_sublocations_are_reported() {
+    let locations = map(vec![location("Plains", &[("north", &[]), ("north", &[])])]);
+    assert_eq!(problems(&locations, "Plains::north"), vec![
+      Problem::DuplicateSublocation(String::from("Plains::north")).to_string()
+    ]);
+  }
+}
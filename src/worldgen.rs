@@ -0,0 +1,208 @@
+/**
+ * Procedural generation of WorldLocations. The same name, seed, Biome and
+ * size always give the same location, so a generated place can be
+ * recreated or shared by its seed alone.
+ *
+ * Sublocations are laid out on a grid by a random walk from the first one
+ * (the entrance), each joined to the one it grew from by a pair of exits
+ * in opposite directions, so every sublocation can be reached.
+ */
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use crate::world_map::{
+  Biome,
+  Description,
+  Direction,
+  Exit,
+  Instance,
+  SubLocation,
+  WorldLocation,
+  WorldLocationAttrs
+};
+
+pub const MAX_SUBLOCATIONS: usize = 20;
+
+// numbers the instances made since the server started, to keep their names apart
+static NEXT_INSTANCE_ID: AtomicU64 = AtomicU64::new(1);
+
+// kinds of place found in each ecosystem, used as sublocation types
+fn sublocation_types(biome: &Biome) -> &'static [&'static str] {
+  if biome.urban {
+    return &["street", "square", "market", "alley", "courtyard"];
+  }
+  match biome.eco.as_str() {
+    "forest" => &["clearing", "thicket", "grove", "glade", "hollow"],
+    "desert" => &["dune", "oasis", "mesa", "canyon", "salt_flat"],
+    "mountains" => &["ridge", "pass", "cave", "cliff", "summit"],
+    "swamp" => &["bog", "marsh", "mire", "fen", "bayou"],
+    "dungeon" | "cave" => &["chamber", "tunnel", "cavern", "pit", "crypt"],
+    _ => &["field", "meadow", "hill", "creek", "grassland"]
+  }
+}
+
+const ADJECTIVES: [&str; 8] = [
+  "quiet", "windswept", "shadowy", "overgrown", "sunlit", "misty", "desolate", "peaceful"
+];
+
+fn step(position: (i32, i32), direction: Direction) -> (i32, i32) {
+  match direction {
+    Direction::North => (position.0, position.1 + 1),
+    Direction::South => (position.0, position.1 - 1),
+    Direction::East => (position.0 + 1, position.1),
+    Direction::West => (position.0 - 1, position.1),
+    Direction::Up | Direction::Down => position
+  }
+}
+
+// a WorldLocation of `size` sublocations (at most MAX_SUBLOCATIONS), the first of which is the entrance
+pub fn generate(name: &str, seed: u64, biome: Biome, size: usize) -> WorldLocation {
+  let mut rng = StdRng::seed_from_u64(seed);
+  let size = size.clamp(1, MAX_SUBLOCATIONS);
+  let types = sublocation_types(&biome);
+
+  let mut sublocations: Vec<SubLocation> = (0..size).map(|i| {
+    let t = types.choose(&mut rng).unwrap_or(&"field");
+    let adjective = ADJECTIVES.choose(&mut rng).unwrap_or(&"quiet");
+    SubLocation {
+      name: format!("{}_{}", t, i + 1),
+      t: String::from(*t),
+      description: Description {
+        text: format!("A {} {}.", adjective, t.replace('_', " ")),
+        variants: vec![]
+      },
+      neighbors: vec![],
+      exits: vec![],
+      items: vec![],
+      active_users: vec![],
+      npcs: vec![]
+    }
+  }).collect();
+
+  let directions = [Direction::North, Direction::South, Direction::East, Direction::West];
+  let mut positions: Vec<(i32, i32)> = vec![(0, 0)];
+  let mut occupied: HashMap<(i32, i32), usize> = HashMap::from([((0, 0), 0)]);
+  for i in 1..size {
+    // grow from a random sublocation in a random direction until a free spot turns up
+    loop {
+      let from = rng.gen_range(0..positions.len());
+      let direction = *directions.choose(&mut rng).unwrap();
+      let position = step(positions[from], direction);
+      if occupied.contains_key(&position) {
+        continue;
+      }
+      occupied.insert(position, i);
+      positions.push(position);
+      let (from_id, to_id) = (
+        format!("{}::{}", name, sublocations[from].name),
+        format!("{}::{}", name, sublocations[i].name)
+      );
+      sublocations[from].exits.push(Exit::new(direction, &to_id));
+      sublocations[i].exits.push(Exit::new(direction.opposite(), &from_id));
+      break;
+    }
+  }
+
+  let spawn_rate: f32 = if biome.urban { rng.gen_range(0.0..0.1) } else { rng.gen_range(0.05..0.3) };
+  WorldLocation {
+    name: String::from(name),
+    attrs: WorldLocationAttrs {
+      treasure_chest_spawn_rate: (spawn_rate * 100.0).round() / 100.0,
+      description: Description {
+        text: format!("You are somewhere in {}.", name.replace('_', " ")),
+        variants: vec![]
+      },
+      biome,
+      sublocations
+    },
+    instance: None
+  }
+}
+
+/* A temporary location for a party, with a way back to `origin` from its
+   entrance. The party is empty until the World puts its first member in,
+   and the `invited` players join it by accepting. It's torn down by the
+   World once everyone has left. */
+pub fn generate_instance(
+  seed: u64,
+  biome: Biome,
+  size: usize,
+  invited: Vec<String>,
+  origin: &str
+) -> WorldLocation {
+  let id = NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed);
+  let name = format!("Instance_{}", id);
+  let mut location = generate(&name, seed, biome, size);
+  let mut way_back = Exit::new(Direction::Up, origin);
+  way_back.label = Some(String::from("the way back"));
+  location.attrs.sublocations[0].exits.push(way_back);
+  location.instance = Some(Instance {
+    party: vec![],
+    invited,
+    origin: String::from(origin)
+  });
+  location
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashSet;
+
+  fn plains() -> Biome {
+    Biome { eco: String::from("plains"), urban: false }
+  }
+
+  #[test]
+  fn same_seed_gives_same_location() {
+    let first = serde_json::to_value(generate("Test", 42, plains(), 8)).unwrap();
+    let second = serde_json::to_value(generate("Test", 42, plains(), 8)).unwrap();
+    assert_eq!(first, second);
+  }
+
+  #[test]
+  fn size_is_clamped() {
+    assert_eq!(generate("Test", 42, plains(), 0).attrs.sublocations.len(), 1);
+    assert_eq!(generate("Test", 42, plains(), 100).attrs.sublocations.len(), MAX_SUBLOCATIONS);
+  }
+
+  #[test]
+  fn every_sublocation_can_be_reached_through_exits() {
+    for seed in 0..20 {
+      let location = generate("Test", seed, plains(), 12);
+      let exits_of = |location_id: &str| location.attrs.sublocations.iter()
+        .find(|sublocation| format!("Test::{}", sublocation.name) == location_id)
+        .unwrap()
+        .exits
+        .clone();
+      let entrance = format!("Test::{}", location.attrs.sublocations[0].name);
+      let mut reached: HashSet<String> = HashSet::from([entrance.clone()]);
+      let mut queue = vec![entrance];
+      while let Some(location_id) = queue.pop() {
+        for exit in exits_of(&location_id) {
+          // every exit has one leading back the opposite way
+          assert!(exits_of(&exit.to).iter()
+            .any(|back| back.to == location_id && back.direction == exit.direction.opposite()));
+          if reached.insert(exit.to.clone()) {
+            queue.push(exit.to);
+          }
+        }
+      }
+      assert_eq!(reached.len(), 12, "seed {}", seed);
+    }
+  }
+
+  #[test]
+  fn instance_leads_back_to_its_origin() {
+    let location = generate_instance(42, plains(), 5, vec![String::from("bob")], "Plains::north");
+    let instance = location.instance.as_ref().unwrap();
+    assert!(instance.party.is_empty());
+    assert_eq!(instance.invited, vec![String::from("bob")]);
+    assert_eq!(instance.origin, "Plains::north");
+    let way_back = location.attrs.sublocations[0].exit(Direction::Up).unwrap();
+    assert_eq!(way_back.to, "Plains::north");
+  }
+}